@@ -1 +1,44 @@
-pub struct Config {}
+use std::time::Duration;
+
+use crate::database::{
+    backup::Retention,
+    cache::{self, ShardCache},
+    scheduler::RequestBudget,
+};
+
+pub struct Config {
+    /// how often dirty year files are written back to disk.
+    pub flush_interval: Duration,
+    /// estimated bytes of year files kept loaded across all servers,
+    /// once for writing and once more for read only queries.
+    pub shard_cache_capacity: usize,
+    /// how often the database directory is snapshotted.
+    pub backup_interval: Duration,
+    /// which snapshots are kept once a new one is taken.
+    pub backup_retention: Retention,
+    /// how many history requests backfills may send, shared by every server.
+    pub backfill_budget: RequestBudget,
+}
+impl Config {
+    /// apply the settings shared by the whole process, before any server is opened.
+    pub fn apply(&self) -> Result<(), String> {
+        for cache in [ShardCache::shared(), ShardCache::read_only()] {
+            cache
+                .lock()
+                .map_err(|_| "shard cache lock poisoned".to_string())?
+                .set_capacity(self.shard_cache_capacity);
+        }
+        Ok(())
+    }
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_secs(300),
+            shard_cache_capacity: cache::DEFAULT_CAPACITY,
+            backup_interval: Duration::from_secs(24 * 60 * 60),
+            backup_retention: Retention::default(),
+            backfill_budget: RequestBudget::default(),
+        }
+    }
+}
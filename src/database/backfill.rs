@@ -0,0 +1,554 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use crate::database::{
+    epoch::{LOUIS_EPOCH, now},
+    handle::ServerHandle,
+    server::{OwnedUserUpdate, ServerDatabase},
+    storage::Storage,
+    watermarks::{Checkpoint, Mark, Marks},
+};
+
+/// the most messages discord hands out per history request.
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+// discord ids start with the milliseconds since the first second of 2015.
+const DISCORD_EPOCH_MS: i64 = 1_420_070_400_000;
+
+/// the largest id discord could give a message sent at the unix time `timestamp`.
+pub fn last_id_at(timestamp: f64) -> u64 {
+    let ms = ((timestamp * 1000.0).round() as i64 - DISCORD_EPOCH_MS).max(0) as u64;
+    ((ms + 1) << 22) - 1
+}
+
+/// a message read back from the history of a channel.
+#[derive(Clone, Debug)]
+pub struct HistoryMessage {
+    pub id: u64,
+    pub author_id: usize,
+    pub author_name: String,
+    /// unix seconds, discord reports them to the millisecond.
+    pub timestamp: f64,
+    pub reactions: Vec<HistoryReaction>,
+}
+
+/// a reaction on a `HistoryMessage` and who added it.
+#[derive(Clone, Debug)]
+pub struct HistoryReaction {
+    pub name: String,
+    pub user_id: usize,
+    pub user_name: String,
+}
+
+/// where the history of a channel is read from, e.g. the discord api.
+pub trait HistorySource {
+    /// up to `limit` messages of `channel` with an id greater than `after`, oldest first.
+    /// no messages means the end of the history was reached.
+    fn messages_after(
+        &mut self,
+        channel: usize,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<HistoryMessage>, String>;
+    /// how long to wait before asking again after the last request failed because of a
+    /// rate limit, e.g. the `retry_after` of a discord 429. `None` for any other failure.
+    fn retry_after(&mut self) -> Option<Duration> {
+        None
+    }
+}
+
+/// where a backfill counts its batches.
+pub trait Counter {
+    /// count `updates` and move the marks of `checkpoint` along with them,
+    /// see `ServerDatabase::update_users_checkpointed`.
+    fn count(&mut self, updates: &[OwnedUserUpdate], checkpoint: &Checkpoint)
+    -> Result<(), String>;
+}
+impl<S: Storage> Counter for ServerDatabase<S> {
+    fn count(
+        &mut self,
+        updates: &[OwnedUserUpdate],
+        checkpoint: &Checkpoint,
+    ) -> Result<(), String> {
+        self.update_users_checkpointed(updates, Some(checkpoint))
+    }
+}
+/// a server the bot is counting live at the same time.
+impl<S: Storage + Send + Sync + 'static> Counter for ServerHandle<S> {
+    fn count(
+        &mut self,
+        updates: &[OwnedUserUpdate],
+        checkpoint: &Checkpoint,
+    ) -> Result<(), String> {
+        self.update_users_blocking(updates, checkpoint)
+    }
+}
+
+/// channel histories held in memory, to run a backfill without discord.
+#[derive(Default)]
+pub struct FakeHistory {
+    channels: HashMap<usize, Vec<HistoryMessage>>,
+    /// the channel and id asked to read after of every request made so far.
+    pub requests: Vec<(usize, u64)>,
+    // every nth request fails, asking to retry after the duration.
+    rate_limit: Option<(usize, Duration)>,
+    limited: bool,
+}
+impl FakeHistory {
+    /// fail every `every`th request as if it was rate limited for `retry_after`.
+    pub fn rate_limit(&mut self, every: usize, retry_after: Duration) {
+        self.rate_limit = Some((every.max(1), retry_after));
+    }
+    pub fn push(&mut self, channel: usize, message: HistoryMessage) {
+        let messages = self.channels.entry(channel).or_default();
+        let i = messages.partition_point(|m| m.id <= message.id);
+        messages.insert(i, message);
+    }
+}
+impl HistorySource for FakeHistory {
+    fn messages_after(
+        &mut self,
+        channel: usize,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<HistoryMessage>, String> {
+        self.requests.push((channel, after));
+        self.limited = self
+            .rate_limit
+            .is_some_and(|(every, _)| self.requests.len().is_multiple_of(every));
+        if self.limited {
+            Err(format!("rate limited reading channel {channel}"))?
+        }
+        Ok(self
+            .channels
+            .get(&channel)
+            .map(|messages| {
+                let i = messages.partition_point(|m| m.id <= after);
+                messages[i..].iter().take(limit).cloned().collect()
+            })
+            .unwrap_or_default())
+    }
+    fn retry_after(&mut self) -> Option<Duration> {
+        self.rate_limit
+            .filter(|_| self.limited)
+            .map(|(_, retry_after)| retry_after)
+    }
+}
+
+/// what a single batch of a backfill counted.
+#[derive(Debug)]
+pub struct BatchOutcome {
+    pub messages: usize,
+    /// messages read again which an earlier backfill or an import already counted.
+    pub duplicates: usize,
+    pub updates: usize,
+    /// where the channel resumes from now.
+    pub pointer: f64,
+}
+
+/// everything a backfill counted in one channel.
+#[derive(Default, Debug)]
+pub struct ChannelBackfill {
+    pub channel: usize,
+    pub batches: usize,
+    pub messages: usize,
+    pub duplicates: usize,
+}
+
+/// reads the history of the channels of a server into its database, batch by batch.
+/// every batch is counted along with a `Checkpoint` moving the backfill frontier of its channel
+/// to its last message and the pointer in the `BatchCache` to when that was sent, so an
+/// interrupted backfill picks up right after the last batch which was journaled.
+/// a channel is read up to where live counting began in it, see `Watermarks`.
+/// backfills of several servers share the same `Marks`, they hold the pointers of them all.
+pub struct Backfill<H> {
+    source: H,
+    marks: Marks,
+    server_id: usize,
+    batch_size: usize,
+    until: f64,
+}
+impl<H: HistorySource> Backfill<H> {
+    /// backfill `server_id` with messages sent before the unix time `until`, or before
+    /// the first message counted live in their channel if that comes earlier.
+    /// the database counting the batches has to apply its checkpoints to the same `marks`.
+    pub fn new(source: H, marks: Marks, server_id: usize, until: f64) -> Self {
+        Self {
+            source,
+            marks,
+            server_id,
+            batch_size: DEFAULT_BATCH_SIZE,
+            until,
+        }
+    }
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+    pub fn source(&self) -> &H {
+        &self.source
+    }
+    pub fn source_mut(&mut self) -> &mut H {
+        &mut self.source
+    }
+    pub fn server_id(&self) -> usize {
+        self.server_id
+    }
+    /// the unix time `channel` has been read up to. a channel which was never backfilled starts
+    /// at the louis epoch, days before it cannot be stored.
+    pub fn pointer(&self, channel: usize) -> Result<f64, String> {
+        Ok(self
+            .marks
+            .cache()?
+            .pointer(self.server_id, channel)
+            // just before midnight, so a message sent right at it is still read.
+            .unwrap_or(LOUIS_EPOCH.timestamp() as f64 - 0.001))
+    }
+    /// the id `channel` is read on after, the backfill frontier or whatever was sent up to the pointer.
+    fn resume_after(&self, channel: usize) -> Result<u64, String> {
+        let frontier = self.marks.watermarks()?.backfilled(self.server_id, channel);
+        match frontier {
+            Some(frontier) => Ok(frontier.id),
+            None => Ok(last_id_at(self.pointer(channel)?)),
+        }
+    }
+    /// how much of the history of `channel` has been read, from 0 to 1. it spans from the
+    /// louis epoch to where the backfill stops, which is never later than now.
+    pub fn progress_of(&self, channel: usize) -> Result<f64, String> {
+        let start = LOUIS_EPOCH.timestamp() as f64;
+        let live_from = self
+            .marks
+            .watermarks()?
+            .live_from(self.server_id, channel)
+            .map_or(f64::MAX, |mark| mark.timestamp);
+        let end = self.until.min(live_from).min(now());
+        if end <= start {
+            return Ok(1.0);
+        }
+        Ok(((self.pointer(channel)? - start) / (end - start)).clamp(0.0, 1.0))
+    }
+    /// count the next batch of `channel` into `database` and checkpoint it,
+    /// `None` once every message before `until` has been counted.
+    pub fn step<C: Counter>(
+        &mut self,
+        database: &mut C,
+        channel: usize,
+    ) -> Result<Option<BatchOutcome>, String> {
+        let after = self.resume_after(channel)?;
+        let mut messages = self
+            .source
+            .messages_after(channel, after, self.batch_size)?;
+        messages.retain(|m| m.id > after);
+        // the batch is checked against live counting and claimed under one lock,
+        // a message live counting sees afterwards is already past the frontier.
+        let mut watermarks = self.marks.watermarks()?;
+        let live_from = watermarks.live_from(self.server_id, channel);
+        let reached = messages
+            .iter()
+            .position(|m| m.timestamp >= self.until || live_from.is_some_and(|l| m.id >= l.id))
+            .unwrap_or(messages.len());
+        messages.truncate(reached);
+        let Some(read) = messages.last().map(|m| Mark {
+            id: m.id,
+            timestamp: m.timestamp,
+        }) else {
+            return Ok(None);
+        };
+        let total = messages.len();
+        messages.retain(|m| {
+            watermarks
+                .imported(self.server_id, channel, m.author_id)
+                .is_none_or(|i| m.id > i.id)
+        });
+        let duplicates = total - messages.len();
+        watermarks.skip_backfilled(self.server_id, channel, duplicates);
+        let claimed = watermarks.claim_backfilled(self.server_id, channel, Some(read));
+        drop(watermarks);
+        let mut tally = Tally::default();
+        messages.iter().for_each(|message| tally.add(message));
+        let updates = tally.into_updates();
+        let checkpoint = Checkpoint {
+            pointer: Some(read.timestamp),
+            backfilled: Some(read),
+            ..Checkpoint::new(self.server_id, channel)
+        };
+        if let Err(e) = database.count(&updates, &checkpoint) {
+            self.marks
+                .watermarks()?
+                .claim_backfilled(self.server_id, channel, claimed);
+            return Err(e);
+        }
+        Ok(Some(BatchOutcome {
+            messages: messages.len(),
+            duplicates,
+            updates: updates.len(),
+            pointer: read.timestamp,
+        }))
+    }
+    /// backfill every channel in `channels` up to `until`, one after the other.
+    pub fn run<C: Counter>(
+        &mut self,
+        database: &mut C,
+        channels: &[usize],
+    ) -> Result<Vec<ChannelBackfill>, String> {
+        let mut backfilled = Vec::new();
+        for channel in channels {
+            let mut progress = ChannelBackfill {
+                channel: *channel,
+                ..ChannelBackfill::default()
+            };
+            while let Some(outcome) = self.step(database, *channel)? {
+                progress.batches += 1;
+                progress.messages += outcome.messages;
+                progress.duplicates += outcome.duplicates;
+            }
+            backfilled.push(progress);
+        }
+        Ok(backfilled)
+    }
+}
+
+/// counts of messages per user and hour, reactions count for whoever added them.
+#[derive(Default)]
+pub struct Tally {
+    updates: BTreeMap<(usize, i64), OwnedUserUpdate>,
+}
+impl Tally {
+    /// messages are expected oldest first, the name of a user is the latest one seen.
+    pub fn add(&mut self, message: &HistoryMessage) {
+        self.update_of(message.author_id, &message.author_name, message.timestamp)
+            .messages += 1;
+        for reaction in &message.reactions {
+            let reactions = &mut self
+                .update_of(reaction.user_id, &reaction.user_name, message.timestamp)
+                .reactions;
+            match reactions
+                .iter_mut()
+                .find(|(name, _)| *name == reaction.name)
+            {
+                Some((_, count)) => *count += 1,
+                None => reactions.push((reaction.name.clone(), 1)),
+            }
+        }
+    }
+    /// one update per user and hour.
+    pub fn into_updates(self) -> Vec<OwnedUserUpdate> {
+        self.updates.into_values().collect()
+    }
+    fn update_of(&mut self, id: usize, name: &str, timestamp: f64) -> &mut OwnedUserUpdate {
+        let hour = (timestamp.floor() as i64).div_euclid(3600) * 3600;
+        let update = self.updates.entry((id, hour)).or_insert(OwnedUserUpdate {
+            id,
+            name: String::new(),
+            messages: 0,
+            reactions: Vec::new(),
+            timestamp: hour,
+        });
+        update.name = name.to_string();
+        update
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        counted, storage::MemoryStorage, test_database, test_dir, watermarks::WATERMARKS_FILE,
+    };
+
+    const JUNE_2025: f64 = 1_750_000_000.0;
+    const SERVER: usize = 1;
+    const CHANNEL: usize = 10;
+
+    /// the `n`th message sent in the millisecond of `timestamp`.
+    fn message(timestamp: f64, n: u64, author_id: usize) -> HistoryMessage {
+        HistoryMessage {
+            id: last_id_at(timestamp - 0.001) + 1 + n,
+            author_id,
+            author_name: format!("user {author_id}"),
+            timestamp,
+            reactions: Vec::new(),
+        }
+    }
+    fn backfill(history: FakeHistory, marks: &Marks) -> Backfill<FakeHistory> {
+        let mut backfill = Backfill::new(history, marks.clone(), SERVER, JUNE_2025 + 3600.0);
+        backfill.set_batch_size(2);
+        backfill
+    }
+
+    #[test]
+    fn reads_messages_sharing_a_timestamp_across_batches() {
+        let path = test_dir("backfill-ties");
+        let marks = Marks::open(&path).unwrap();
+        let mut database = test_database(&path, &marks);
+        let mut history = FakeHistory::default();
+        (0..5).for_each(|n| history.push(CHANNEL, message(JUNE_2025, n, 1)));
+        history.push(CHANNEL, message(JUNE_2025 + 60.0, 0, 2));
+        // sent after `until`, left for live counting.
+        history.push(CHANNEL, message(JUNE_2025 + 7200.0, 0, 2));
+        let mut backfill = backfill(history, &marks);
+        let channels = backfill.run(&mut database, &[CHANNEL]).unwrap();
+        assert_eq!((channels[0].batches, channels[0].messages), (3, 6));
+        assert_eq!(counted(&database).messages, 6);
+        assert_eq!(backfill.pointer(CHANNEL).unwrap(), JUNE_2025 + 60.0);
+        let after: Vec<u64> = backfill.source().requests.iter().map(|r| r.1).collect();
+        assert!(after.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn a_crash_after_journaling_a_batch_resumes_right_after_it() {
+        let path = test_dir("backfill-crash");
+        let mut history = FakeHistory::default();
+        (0..4).for_each(|n| history.push(CHANNEL, message(JUNE_2025 + n as f64, 0, 1)));
+        let marks = Marks::open(&path).unwrap();
+        let mut database = test_database(&path, &marks);
+        backfill(history, &marks)
+            .step(&mut database, CHANNEL)
+            .unwrap();
+        // the checkpoint only lives in the journal until the database is flushed.
+        assert!(!path.join(WATERMARKS_FILE).exists());
+        // the process dies, the marks and counts in memory with it.
+        drop(database);
+        let marks = Marks::open(&path).unwrap();
+        let mut database = test_database(&path, &marks);
+        assert_eq!(counted(&database).messages, 2);
+        let frontier = marks.watermarks().unwrap().backfilled(SERVER, CHANNEL);
+        assert_eq!(frontier.map(|mark| mark.timestamp), Some(JUNE_2025 + 1.0));
+        let mut history = FakeHistory::default();
+        (0..4).for_each(|n| history.push(CHANNEL, message(JUNE_2025 + n as f64, 0, 1)));
+        let mut backfill = backfill(history, &marks);
+        backfill.run(&mut database, &[CHANNEL]).unwrap();
+        assert_eq!(counted(&database).messages, 4);
+        database.flush().unwrap();
+        let marks = Marks::open(&path).unwrap();
+        assert_eq!(
+            marks.cache().unwrap().pointer(SERVER, CHANNEL),
+            Some(JUNE_2025 + 3.0)
+        );
+    }
+
+    #[test]
+    fn rate_limited_requests_are_read_again() {
+        let path = test_dir("backfill-rate-limit");
+        let marks = Marks::open(&path).unwrap();
+        let mut database = test_database(&path, &marks);
+        let mut history = FakeHistory::default();
+        (0..7).for_each(|n| history.push(CHANNEL, message(JUNE_2025 + n as f64, 0, 1)));
+        history.rate_limit(2, Duration::from_secs(1));
+        let mut backfill = backfill(history, &marks);
+        let mut failed = 0;
+        loop {
+            match backfill.step(&mut database, CHANNEL) {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(_) => {
+                    assert!(backfill.source_mut().retry_after().is_some());
+                    failed += 1;
+                }
+            }
+        }
+        assert!(failed > 0);
+        assert_eq!(counted(&database).messages, 7);
+    }
+
+    #[test]
+    fn live_counting_and_a_backfill_meet_without_counting_twice() {
+        let path = test_dir("backfill-live");
+        let sent: Vec<HistoryMessage> = (0..6)
+            .map(|n| message(JUNE_2025 + n as f64, 0, 1))
+            .collect();
+        let mut history = FakeHistory::default();
+        sent.iter().for_each(|m| history.push(CHANNEL, m.clone()));
+        let live = |database: &mut ServerDatabase<MemoryStorage>, m: &HistoryMessage| {
+            let mut tally = Tally::default();
+            tally.add(m);
+            let mark = Mark {
+                id: m.id,
+                timestamp: m.timestamp,
+            };
+            database
+                .count_live(SERVER, CHANNEL, mark, &tally.into_updates())
+                .unwrap()
+        };
+        let marks = Marks::open(&path).unwrap();
+        let mut database = test_database(&path, &marks);
+        let mut backfill = backfill(history, &marks);
+        backfill.step(&mut database, CHANNEL).unwrap();
+        // the bot comes online while the backfill is still going, it sees an edit of an old
+        // message and then new ones.
+        assert!(!live(&mut database, &sent[1]));
+        assert!(live(&mut database, &sent[4]));
+        assert!(live(&mut database, &sent[5]));
+        let channels = backfill.run(&mut database, &[CHANNEL]).unwrap();
+        assert_eq!(channels[0].messages, 2);
+        assert_eq!(counted(&database).messages, 6);
+        assert_eq!(marks.watermarks().unwrap().report()[0].2.live, 1);
+        // the process dies, both are replayed along with where they met.
+        drop(database);
+        let marks = Marks::open(&path).unwrap();
+        let database = test_database(&path, &marks);
+        assert_eq!(counted(&database).messages, 6);
+        let watermarks = marks.watermarks().unwrap();
+        assert_eq!(
+            watermarks.live_from(SERVER, CHANNEL).unwrap().id,
+            sent[4].id
+        );
+        assert_eq!(
+            watermarks.backfilled(SERVER, CHANNEL).unwrap().id,
+            sent[3].id
+        );
+    }
+
+    #[test]
+    fn an_edit_between_the_backfill_and_live_counting_is_left_to_the_backfill() {
+        let path = test_dir("backfill-live-edit");
+        let sent: Vec<HistoryMessage> = (0..6)
+            .map(|n| message(JUNE_2025 + n as f64, 0, 1))
+            .collect();
+        let mut history = FakeHistory::default();
+        sent.iter().for_each(|m| history.push(CHANNEL, m.clone()));
+        let marks = Marks::open(&path).unwrap();
+        let mut database = test_database(&path, &marks);
+        let mut backfill = backfill(history, &marks);
+        backfill.step(&mut database, CHANNEL).unwrap();
+        for m in [&sent[4], &sent[5], &sent[2]] {
+            let mut tally = Tally::default();
+            tally.add(m);
+            let mark = Mark {
+                id: m.id,
+                timestamp: m.timestamp,
+            };
+            database
+                .count_live(SERVER, CHANNEL, mark, &tally.into_updates())
+                .unwrap();
+        }
+        // the edit of the third message came after live counting started past it.
+        assert_eq!(counted(&database).messages, 4);
+        backfill.run(&mut database, &[CHANNEL]).unwrap();
+        assert_eq!(counted(&database).messages, 6);
+        assert_eq!(marks.watermarks().unwrap().report()[0].2.live, 1);
+    }
+
+    #[test]
+    fn a_batch_which_could_not_be_counted_gives_up_its_claim() {
+        struct Failing;
+        impl Counter for Failing {
+            fn count(&mut self, _: &[OwnedUserUpdate], _: &Checkpoint) -> Result<(), String> {
+                Err("disk full".to_string())
+            }
+        }
+        let marks = Marks::open(&test_dir("backfill-failed-count")).unwrap();
+        let mut history = FakeHistory::default();
+        history.push(CHANNEL, message(JUNE_2025, 0, 1));
+        assert!(
+            backfill(history, &marks)
+                .step(&mut Failing, CHANNEL)
+                .is_err()
+        );
+        assert_eq!(
+            marks.watermarks().unwrap().backfilled(SERVER, CHANNEL),
+            None
+        );
+        assert_eq!(marks.cache().unwrap().pointer(SERVER, CHANNEL), None);
+    }
+}
@@ -0,0 +1,491 @@
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fs::{self, File},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use tokio::{task, time};
+
+use crate::database::{
+    cache::ShardCache,
+    flusher::shutdown_signal,
+    handle::DatabaseRegistry,
+    lock::{DirectoryLock, LOCK_FILE},
+    server::{BATCH_CACHE_FILE, ServerFile},
+    storage::Storage,
+    watermarks::{Marks, WATERMARKS_FILE},
+};
+
+// written by `ColorConfig`.
+const COLORS_FILE: &str = "colors.json";
+const SNAPSHOT_FORMAT: &str = "snapshot-%Y%m%dT%H%M%S%.3fZ.tar.gz";
+// snapshots taken before names had milliseconds.
+const SECOND_SNAPSHOT_FORMAT: &str = "snapshot-%Y%m%dT%H%M%SZ.tar.gz";
+const STAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+/// how many snapshots survive a prune, the newest one always does.
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    /// the newest snapshot of each of the last `daily` days which have one.
+    pub daily: usize,
+    /// the newest snapshot of each of the last `weekly` iso weeks which have one.
+    pub weekly: usize,
+}
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            daily: 7,
+            weekly: 4,
+        }
+    }
+}
+
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub taken_at: DateTime<Utc>,
+}
+
+/// timestamped archives of every server directory in a database directory,
+/// along with `colors.json`, the batch cache and the watermarks.
+#[derive(Clone)]
+pub struct Backups {
+    database: PathBuf,
+    directory: PathBuf,
+    retention: Retention,
+}
+impl Backups {
+    /// snapshots of `database` are kept in `directory`.
+    pub fn new(database: &Path, directory: &Path, retention: Retention) -> Self {
+        Self {
+            database: database.to_path_buf(),
+            directory: directory.to_path_buf(),
+            retention,
+        }
+    }
+    /// every snapshot in the backup directory, newest first.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>, String> {
+        if !self.directory.exists() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = fs::read_dir(&self.directory)
+            .map_err(|e| format!("could not read directory {:?}: {e}", self.directory))?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let taken_at = NaiveDateTime::parse_from_str(&name, SNAPSHOT_FORMAT)
+                    .or_else(|_| NaiveDateTime::parse_from_str(&name, SECOND_SNAPSHOT_FORMAT))
+                    .ok()?;
+                Some(Snapshot {
+                    path: entry.path(),
+                    taken_at: taken_at.and_utc(),
+                })
+            })
+            .collect::<Vec<Snapshot>>();
+        snapshots.sort_by_key(|s| Reverse(s.taken_at));
+        Ok(snapshots)
+    }
+    /// archive the database as it is on disk, flush any open servers first to include their changes.
+    /// the archive only appears under its final name once it is complete.
+    pub fn snapshot(&self) -> Result<PathBuf, String> {
+        fs::create_dir_all(&self.directory)
+            .map_err(|e| format!("could not create directory {:?}: {e}", self.directory))?;
+        let path = unused_path(&self.directory, SNAPSHOT_FORMAT);
+        let tmp = path.with_extension("tmp");
+        let result = self.write_archive(&tmp).and_then(|_| {
+            fs::rename(&tmp, &path).map_err(|e| format!("could not replace {path:?}: {e}"))
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result.map(|_| path)
+    }
+    fn write_archive(&self, path: &Path) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| format!("could not create {path:?}: {e}"))?;
+        let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        let append_error = |e| format!("could not write snapshot {path:?}: {e}");
+        for name in [COLORS_FILE, BATCH_CACHE_FILE, WATERMARKS_FILE] {
+            let source = self.database.join(name);
+            if source.exists() {
+                archive
+                    .append_path_with_name(&source, name)
+                    .map_err(append_error)?;
+            }
+        }
+        for server in self.servers()? {
+            // a writer may hold the directory, its files are still replaced atomically.
+            let directory = self.database.join(&server);
+            let _lock = DirectoryLock::shared(&directory)?;
+            for entry in fs::read_dir(&directory)
+                .map_err(|e| format!("could not read directory {directory:?}: {e}"))?
+                .filter_map(|entry| entry.ok())
+            {
+                let Ok(file_name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                // the lock belongs to whoever holds the directory, temporaries are unfinished writes.
+                if file_name == LOCK_FILE
+                    || (file_name.starts_with('.') && file_name.ends_with(".tmp"))
+                {
+                    continue;
+                }
+                let (source, name) = (entry.path(), Path::new(&server).join(&file_name));
+                let appended = match source.is_dir() {
+                    true => archive.append_dir_all(&name, &source),
+                    false => archive.append_path_with_name(&source, &name),
+                };
+                match appended {
+                    // removed by the writer since the directory was listed.
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    appended => appended.map_err(append_error)?,
+                }
+            }
+        }
+        archive
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .and_then(|file| file.sync_all())
+            .map_err(append_error)
+    }
+    /// every directory of the database, whichever storage its server uses.
+    /// hidden ones are left over from restores.
+    fn servers(&self) -> Result<Vec<String>, String> {
+        let mut servers = Vec::new();
+        for entry in fs::read_dir(&self.database)
+            .map_err(|e| format!("could not read directory {:?}: {e}", self.database))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+        {
+            match entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') => servers.push(name),
+                _ => {}
+            }
+        }
+        servers.sort();
+        Ok(servers)
+    }
+    /// delete every snapshot the retention rules do not keep, returns the deleted paths.
+    pub fn prune(&self) -> Result<Vec<PathBuf>, String> {
+        let snapshots = self.snapshots()?;
+        let mut keep = HashSet::from([0]);
+        let mut days = Vec::new();
+        let mut weeks = Vec::new();
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let day = snapshot.taken_at.date_naive();
+            if days.len() < self.retention.daily && !days.contains(&day) {
+                days.push(day);
+                keep.insert(i);
+            }
+            let week = (day.iso_week().year(), day.iso_week().week());
+            if weeks.len() < self.retention.weekly && !weeks.contains(&week) {
+                weeks.push(week);
+                keep.insert(i);
+            }
+        }
+        let mut removed = Vec::new();
+        for (_, snapshot) in snapshots
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !keep.contains(i))
+        {
+            fs::remove_file(&snapshot.path)
+                .map_err(|e| format!("could not remove snapshot {:?}: {e}", snapshot.path))?;
+            removed.push(snapshot.path);
+        }
+        Ok(removed)
+    }
+    /// put the contents of `snapshot` back into the database, returns the restored entries.
+    /// the whole archive is unpacked and validated before anything is replaced, and every
+    /// replaced server directory or file is moved into `.replaced-<time>` in the database.
+    /// servers in the snapshot must not be open for writing.
+    pub fn restore(&self, snapshot: &Path) -> Result<Vec<String>, String> {
+        let staging = unused_path(&self.database, &format!(".restore-{STAMP_FORMAT}"));
+        let result = self
+            .unpack(snapshot, &staging)
+            .and_then(|entries| self.validate(&staging, &entries).map(|_| entries))
+            .and_then(|entries| {
+                let replaced = unused_path(&self.database, &format!(".replaced-{STAMP_FORMAT}"));
+                self.swap_in(&staging, &replaced, &entries).map(|_| entries)
+            });
+        let _ = fs::remove_dir_all(&staging);
+        result
+    }
+    fn unpack(&self, snapshot: &Path, staging: &Path) -> Result<Vec<String>, String> {
+        let file = File::open(snapshot)
+            .map_err(|e| format!("could not open snapshot {snapshot:?}: {e}"))?;
+        tar::Archive::new(GzDecoder::new(file))
+            .unpack(staging)
+            .map_err(|e| format!("could not unpack snapshot {snapshot:?}: {e}"))?;
+        let mut entries = fs::read_dir(staging)
+            .map_err(|e| format!("could not read directory {staging:?}: {e}"))?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect::<Vec<String>>();
+        entries.sort();
+        Ok(entries)
+    }
+    fn validate(&self, staging: &Path, entries: &[String]) -> Result<(), String> {
+        for entry in entries {
+            let path = staging.join(entry);
+            if path.is_dir() {
+                for (_, year) in ServerFile::year_files(staging, entry)? {
+                    ServerFile::load(&year, true)
+                        .map_err(|e| format!("snapshot is not valid: {e}"))?;
+                }
+            } else {
+                let file =
+                    File::open(&path).map_err(|e| format!("could not open {path:?}: {e}"))?;
+                serde_json::from_reader::<_, serde_json::Value>(file)
+                    .map_err(|e| format!("snapshot is not valid, could not parse {entry}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+    /// move every entry of `staging` into the database, the ones it replaces into `replaced`.
+    /// every server is locked before anything moves, and a failed move puts back what was moved.
+    fn swap_in(&self, staging: &Path, replaced: &Path, entries: &[String]) -> Result<(), String> {
+        let mut _locks = Vec::new();
+        for entry in entries.iter().filter(|entry| staging.join(entry).is_dir()) {
+            _locks.push(DirectoryLock::exclusive(&self.database.join(entry))?);
+            // the lock of a replaced server moves aside with it, the restored one brings its own
+            // so the server stays held until everything is in place.
+            _locks.push(DirectoryLock::exclusive(&staging.join(entry))?);
+        }
+        fs::create_dir_all(replaced)
+            .map_err(|e| format!("could not create directory {replaced:?}: {e}"))?;
+        let mut moved: Vec<&str> = Vec::new();
+        for entry in entries {
+            if let Err(e) = self.swap_entry(staging, replaced, entry) {
+                for entry in moved.iter().rev() {
+                    if let Err(undo) = self.unswap_entry(staging, replaced, entry) {
+                        eprintln!("could not put {entry} back after a failed restore: {undo}");
+                    }
+                }
+                return Err(e);
+            }
+            moved.push(entry);
+        }
+        // cached shards of the servers would be written back over what was restored.
+        for cache in [ShardCache::shared(), ShardCache::read_only()] {
+            let mut cache = cache
+                .lock()
+                .map_err(|_| "shard cache lock poisoned".to_string())?;
+            for entry in entries {
+                cache.remove_where(&self.database.join(entry), |_, _| true);
+            }
+        }
+        // the marks held in memory would be, too.
+        Marks::reload_shared(&self.database)
+    }
+    fn swap_entry(&self, staging: &Path, replaced: &Path, entry: &str) -> Result<(), String> {
+        let (source, target) = (staging.join(entry), self.database.join(entry));
+        if target.exists() {
+            fs::rename(&target, replaced.join(entry))
+                .map_err(|e| format!("could not move {target:?} aside: {e}"))?;
+        }
+        fs::rename(&source, &target).map_err(|e| {
+            // the entry itself is put back right away, the ones before it by `swap_in`.
+            let _ = self.unswap_entry(staging, replaced, entry);
+            format!("could not restore {target:?}: {e}")
+        })
+    }
+    /// undo `swap_entry`, whichever part of it happened.
+    fn unswap_entry(&self, staging: &Path, replaced: &Path, entry: &str) -> Result<(), String> {
+        let (source, target, aside) = (
+            staging.join(entry),
+            self.database.join(entry),
+            replaced.join(entry),
+        );
+        if !source.exists() && target.exists() {
+            fs::rename(&target, &source)
+                .map_err(|e| format!("could not move {target:?} back: {e}"))?;
+        }
+        if aside.exists() {
+            fs::rename(&aside, &target)
+                .map_err(|e| format!("could not move {aside:?} back: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// a path in `directory` named after the current time in `format` which nothing uses yet.
+fn unused_path(directory: &Path, format: &str) -> PathBuf {
+    let mut at = Utc::now();
+    loop {
+        let path = directory.join(at.format(format).to_string());
+        if !path.exists() && !path.with_extension("tmp").exists() {
+            return path;
+        }
+        at += chrono::TimeDelta::milliseconds(1);
+    }
+}
+
+/// snapshot the database every `interval` until SIGINT or SIGTERM arrives, pruning after each one.
+/// open servers are flushed first so the snapshot holds everything counted so far.
+pub async fn snapshot_periodically<S: Storage + Send + Sync + 'static>(
+    registry: DatabaseRegistry<S>,
+    backups: Backups,
+    interval: Duration,
+) -> Result<(), String> {
+    let mut ticker = time::interval(interval);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                if let Err(e) = snapshot_now(&registry, backups.clone()).await {
+                    eprintln!("snapshot failed: {e}");
+                }
+            }
+            received = &mut shutdown => return received,
+        }
+    }
+}
+
+async fn snapshot_now<S: Storage + Send + Sync + 'static>(
+    registry: &DatabaseRegistry<S>,
+    backups: Backups,
+) -> Result<(), String> {
+    registry.flush_all().await?;
+    task::spawn_blocking(move || {
+        backups.snapshot()?;
+        backups.prune().map(|_| ())
+    })
+    .await
+    .map_err(|e| format!("snapshot task failed: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::database::{
+        epoch::LouisEpoch, server::ServerDatabase, sqlite::SqliteStorage, test_dir,
+    };
+
+    fn count(database: &Path, messages: usize) {
+        let date = DateTime::from_timestamp(1_750_000_000, 0).unwrap();
+        let mut server = ServerDatabase::new(&database.join("server")).unwrap();
+        server
+            .update_users(&[(1, "louis", messages, &[], date)])
+            .unwrap();
+        server.flush().unwrap();
+    }
+    fn messages(database: &Path) -> usize {
+        ServerDatabase::collect_data(database, "server", 0, LouisEpoch::MAX)
+            .unwrap()
+            .users
+            .iter()
+            .flat_map(|u| u.days().values())
+            .map(|d| d.total())
+            .sum()
+    }
+
+    #[test]
+    fn snapshots_taken_at_once_get_their_own_names() {
+        let directory = test_dir("backup-names");
+        let database = directory.join("database");
+        count(&database, 1);
+        let backups = Backups::new(&database, &directory.join("backups"), Retention::default());
+        let first = backups.snapshot().unwrap();
+        let second = backups.snapshot().unwrap();
+        assert_ne!(first, second);
+        // names from before milliseconds are still listed.
+        fs::write(
+            directory
+                .join("backups")
+                .join("snapshot-20250601T120000Z.tar.gz"),
+            "",
+        )
+        .unwrap();
+        let snapshots = backups.snapshots().unwrap();
+        assert_eq!(snapshots.len(), 3);
+        assert_eq!(snapshots[0].path, second);
+    }
+
+    #[test]
+    fn restore_replaces_what_readers_cached() {
+        let directory = test_dir("backup-restore");
+        let database = directory.join("database");
+        count(&database, 2);
+        let backups = Backups::new(&database, &directory.join("backups"), Retention::default());
+        let snapshot = backups.snapshot().unwrap();
+        count(&database, 3);
+        assert_eq!(messages(&database), 5);
+        assert_eq!(backups.restore(&snapshot).unwrap(), ["server"]);
+        assert_eq!(messages(&database), 2);
+    }
+
+    #[test]
+    fn restore_moves_nothing_while_a_server_is_open() {
+        let directory = test_dir("backup-open");
+        let database = directory.join("database");
+        count(&database, 2);
+        let backups = Backups::new(&database, &directory.join("backups"), Retention::default());
+        let snapshot = backups.snapshot().unwrap();
+        count(&database, 3);
+        let open = ServerDatabase::new(&database.join("server")).unwrap();
+        assert!(backups.restore(&snapshot).is_err());
+        drop(open);
+        assert_eq!(messages(&database), 5);
+    }
+
+    #[test]
+    fn restore_brings_back_the_rollups_and_sidecars_but_no_lock() {
+        let directory = test_dir("backup-rollups");
+        let database = directory.join("database");
+        count(&database, 2);
+        let backups = Backups::new(&database, &directory.join("backups"), Retention::default());
+        let snapshot = backups.snapshot().unwrap();
+        let file = File::open(&snapshot).unwrap();
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+        let mut names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        names.sort();
+        assert!(names.contains(&"server/server_rollups.json".to_string()));
+        assert!(names.contains(&"server/server_2025.applied".to_string()));
+        assert!(!names.iter().any(|name| name.ends_with(LOCK_FILE)));
+        count(&database, 3);
+        backups.restore(&snapshot).unwrap();
+        assert!(database.join("server").join("server_rollups.json").exists());
+        let server = ServerDatabase::new(&database.join("server")).unwrap();
+        assert_eq!(
+            server.rollups().leaderboard(None, None)[0].totals.messages,
+            2
+        );
+    }
+
+    #[test]
+    fn restore_brings_back_servers_stored_in_sqlite() {
+        let directory = test_dir("backup-sqlite");
+        let database = directory.join("database");
+        let count_sqlite = |messages| {
+            let date = DateTime::from_timestamp(1_750_000_000, 0).unwrap();
+            let mut server = ServerDatabase::new_sqlite(&database.join("server")).unwrap();
+            server
+                .update_users(&[(1, "louis", messages, &[], date)])
+                .unwrap();
+            server.flush().unwrap();
+        };
+        count_sqlite(2);
+        let backups = Backups::new(&database, &directory.join("backups"), Retention::default());
+        let snapshot = backups.snapshot().unwrap();
+        count_sqlite(3);
+        assert_eq!(backups.restore(&snapshot).unwrap(), ["server"]);
+        let storage = SqliteStorage::open(&database.join("server").join("server.sqlite3")).unwrap();
+        let users = storage.collect(0, LouisEpoch::MAX).unwrap().users;
+        assert_eq!(
+            users[0].days().values().map(|d| d.total()).sum::<usize>(),
+            2
+        );
+        // the restored server was let go of once it was in place.
+        assert!(ServerDatabase::new_sqlite(&database.join("server")).is_ok());
+    }
+}
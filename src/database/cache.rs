@@ -0,0 +1,213 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use crate::database::server::ServerFile;
+
+pub const DEFAULT_CAPACITY: usize = 256 * 1024 * 1024;
+static SHARED: LazyLock<Arc<Mutex<ShardCache>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(ShardCache::new(DEFAULT_CAPACITY))));
+static READ_ONLY: LazyLock<Arc<Mutex<ShardCache>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(ShardCache::new(DEFAULT_CAPACITY))));
+
+struct CachedShard {
+    file: ServerFile,
+    last_used: u64,
+}
+
+/// year files loaded for any server, bounded by their estimated size in memory.
+/// shards are keyed by server directory and year, the least recently used ones are
+/// flushed if dirty and dropped once the capacity is exceeded.
+pub struct ShardCache {
+    capacity: usize,
+    clock: u64,
+    shards: HashMap<(PathBuf, usize), CachedShard>,
+}
+impl ShardCache {
+    /// `capacity` is in estimated bytes, see `ServerFile::estimated_size`.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            clock: 0,
+            shards: HashMap::new(),
+        }
+    }
+    /// the cache used by every `ServerFiles` which was not given its own.
+    pub fn shared() -> Arc<Mutex<Self>> {
+        SHARED.clone()
+    }
+    /// the cache used by every read only `ServerFiles`, kept apart so a writer never sees its shards.
+    pub fn read_only() -> Arc<Mutex<Self>> {
+        READ_ONLY.clone()
+    }
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict(None);
+    }
+    /// run `f` on the shard of `server` for `year`, loading it with `load` if it is not cached.
+    pub fn with_shard<T>(
+        &mut self,
+        server: &Path,
+        year: usize,
+        load: impl FnOnce() -> Result<ServerFile, String>,
+        f: impl FnOnce(&mut ServerFile) -> T,
+    ) -> Result<T, String> {
+        self.clock += 1;
+        let key = (server.to_path_buf(), year);
+        let loaded = !self.shards.contains_key(&key);
+        if loaded {
+            let file = load()?;
+            self.shards.insert(
+                key.clone(),
+                CachedShard {
+                    file,
+                    last_used: self.clock,
+                },
+            );
+        }
+        let shard = self.shards.get_mut(&key).unwrap();
+        shard.last_used = self.clock;
+        let result = f(&mut shard.file);
+        if loaded {
+            self.evict(Some(&key));
+        }
+        Ok(result)
+    }
+    /// cache a shard loaded elsewhere, unless its year was cached meanwhile.
+    pub fn insert(&mut self, server: &Path, year: usize, file: ServerFile) {
+        self.clock += 1;
+        let key = (server.to_path_buf(), year);
+        if let Entry::Vacant(vacant) = self.shards.entry(key.clone()) {
+            vacant.insert(CachedShard {
+                file,
+                last_used: self.clock,
+            });
+            self.evict(Some(&key));
+        }
+    }
+    /// the years of `server` currently in memory.
+    pub fn years(&self, server: &Path) -> Vec<usize> {
+        self.shards
+            .keys()
+            .filter(|(s, _)| s == server)
+            .map(|(_, year)| *year)
+            .collect()
+    }
+    pub fn for_each_shard(&mut self, server: &Path, mut f: impl FnMut(&mut ServerFile)) {
+        self.shards
+            .iter_mut()
+            .filter(|((s, _), _)| s == server)
+            .for_each(|(_, shard)| f(&mut shard.file));
+    }
+    /// write every dirty shard of `server`.
+    pub fn flush_server(&mut self, server: &Path) -> Result<(), String> {
+        self.shards
+            .iter_mut()
+            .filter(|((s, _), shard)| s == server && shard.file.is_dirty())
+            .try_for_each(|(_, shard)| shard.file.flush())
+    }
+    /// write every dirty shard of `server` and drop all of them, e.g. once it is closed.
+    /// on failure nothing is dropped, so the changes can still be written later.
+    pub fn remove_server(&mut self, server: &Path) -> Result<(), String> {
+        self.flush_server(server)?;
+        self.shards.retain(|(s, _), _| s != server);
+        Ok(())
+    }
+    /// drop the shards of `server` for which `f` holds, given their year, without writing them.
+    pub fn remove_where(&mut self, server: &Path, mut f: impl FnMut(usize, &ServerFile) -> bool) {
+        self.shards
+            .retain(|(s, year), shard| s != server || !f(*year, &shard.file));
+    }
+    /// write every dirty shard of every server.
+    pub fn flush_all(&mut self) -> Result<(), String> {
+        self.shards
+            .values_mut()
+            .filter(|shard| shard.file.is_dirty())
+            .try_for_each(|shard| shard.file.flush())
+    }
+    // sizes are re-estimated on every pass, shards grow in place as they are updated.
+    fn evict(&mut self, keep: Option<&(PathBuf, usize)>) {
+        let mut used: usize = self.shards.values().map(|s| s.file.estimated_size()).sum();
+        while used > self.capacity {
+            let Some(key) = self
+                .shards
+                .iter()
+                .filter(|(key, _)| Some(*key) != keep)
+                .min_by_key(|(_, shard)| shard.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let shard = self.shards.get_mut(&key).unwrap();
+            if shard.file.is_dirty()
+                && let Err(e) = shard.file.flush()
+            {
+                // dropping it would lose its changes, stay over capacity until it can be written.
+                eprintln!("could not evict {key:?}: {e}");
+                break;
+            }
+            used -= shard.file.estimated_size();
+            self.shards.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::database::{server::ServerFiles, storage::Storage, test_dir};
+
+    // june of 2025 to 2028.
+    const JUNES: [i64; 4] = [1_750_000_000, 1_781_000_000, 1_812_000_000, 1_843_000_000];
+
+    fn count(files: &mut ServerFiles, year: usize) {
+        let date = DateTime::from_timestamp(JUNES[year - 2025], 0).unwrap();
+        files.update(&[(1, "louis", 2, &[], date)], 1).unwrap();
+    }
+    fn stored(directory: &Path, year: usize) -> usize {
+        let path = ServerFile::file_path_in(directory, "server", year);
+        let file = ServerFile::load(&path, true).unwrap();
+        file.get_all_users()[0]
+            .days()
+            .values()
+            .map(|d| d.total())
+            .sum()
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_shards_after_writing_them() {
+        let directory = test_dir("cache-evict");
+        let server = directory.join("server");
+        let cache = Arc::new(Mutex::new(ShardCache::new(usize::MAX)));
+        let mut files = ServerFiles::with_cache(&directory, "server", cache.clone());
+        for year in [2025, 2026, 2027] {
+            count(&mut files, year);
+        }
+        let size = {
+            let cache = cache.lock().unwrap();
+            let sizes = cache.shards.values().map(|s| s.file.estimated_size());
+            sizes.max().unwrap()
+        };
+        cache.lock().unwrap().set_capacity(2 * size);
+        // 2025 was used least recently and dirty, it is written before it is dropped.
+        assert!(!ServerFile::file_path_in(&directory, "server", 2026).exists());
+        let mut years = cache.lock().unwrap().years(&server);
+        years.sort();
+        assert_eq!(years, [2026, 2027]);
+        assert_eq!(stored(&directory, 2025), 2);
+        // using 2026 again makes 2027 the one to go.
+        count(&mut files, 2026);
+        count(&mut files, 2028);
+        let mut years = cache.lock().unwrap().years(&server);
+        years.sort();
+        assert_eq!(years, [2026, 2028]);
+        assert_eq!(stored(&directory, 2027), 2);
+        let cache = cache.lock().unwrap();
+        let used: usize = cache.shards.values().map(|s| s.file.estimated_size()).sum();
+        assert!(used <= 2 * size);
+    }
+}
@@ -0,0 +1,466 @@
+use std::{
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use chrono::DateTime;
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde_derive::Deserialize;
+
+use crate::database::{
+    backfill::{Counter, HistoryMessage, HistoryReaction, Tally},
+    epoch::LOUIS_EPOCH,
+    watermarks::{ChannelMarks, Checkpoint, Mark, Watermarks},
+};
+
+#[derive(Deserialize)]
+struct Guild {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Channel {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct DateRange {
+    after: Option<String>,
+}
+
+// only what is counted, the content, attachments and embeds of a message are skipped.
+#[derive(Deserialize)]
+struct Message {
+    id: String,
+    timestamp: String,
+    author: Author,
+    #[serde(default)]
+    reactions: Vec<Reaction>,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    id: String,
+    name: String,
+    nickname: Option<String>,
+}
+impl Author {
+    fn parse(self) -> Result<(usize, String), String> {
+        Ok((parse_id(&self.id)?, self.nickname.unwrap_or(self.name)))
+    }
+}
+
+#[derive(Deserialize)]
+struct Reaction {
+    emoji: Emoji,
+    count: usize,
+    // older exports leave out who reacted.
+    #[serde(default)]
+    users: Vec<Author>,
+}
+
+#[derive(Deserialize)]
+struct Emoji {
+    name: String,
+}
+
+/// what importing one archive counted, or would count on a dry run.
+#[derive(Default, Debug)]
+pub struct ArchiveSummary {
+    pub channel: usize,
+    pub channel_name: String,
+    pub messages: usize,
+    pub reactions: usize,
+    /// reactions the archive does not say the users of, they cannot be counted.
+    pub unattributed_reactions: usize,
+    /// messages which were already counted, see `ChannelMarks::admits`.
+    pub duplicates: usize,
+    /// messages sent before the louis epoch, there are no days to hold them.
+    pub too_old: usize,
+    pub users: usize,
+    pub updates: usize,
+    /// unix time of the first and last message counted.
+    pub first: Option<f64>,
+    pub last: Option<f64>,
+    pub dry_run: bool,
+}
+
+/// imports the json archives DiscordChatExporter writes of a channel, one per channel.
+/// an archive counts like a backfill of its channel would, along with a `Checkpoint` moving the
+/// backfill frontier and pointer of the channel past it, so backfills and other imports skip
+/// what it counted. archives exported with a filter leave messages out and are refused.
+pub struct ChatExportImporter<'a> {
+    server_id: usize,
+    watermarks: &'a Mutex<Watermarks>,
+    dry_run: bool,
+}
+impl<'a> ChatExportImporter<'a> {
+    /// `watermarks` have to be the ones the databases counting the archives apply their
+    /// checkpoints to.
+    pub fn new(server_id: usize, watermarks: &'a Mutex<Watermarks>) -> Self {
+        Self {
+            server_id,
+            watermarks,
+            dry_run: false,
+        }
+    }
+    /// read the archives and summarize them without counting anything or moving any marks.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+    /// import every archive in `paths` in order, stopping at the first one which fails.
+    pub fn import_all<C: Counter>(
+        &self,
+        paths: &[&Path],
+        database: &mut C,
+    ) -> Result<Vec<ArchiveSummary>, String> {
+        paths
+            .iter()
+            .map(|path| self.import(path, database))
+            .collect()
+    }
+    /// count the archive at `path` into `database`. it is streamed a message at a time, only the
+    /// counts per user and hour are kept in memory.
+    pub fn import<C: Counter>(
+        &self,
+        path: &Path,
+        database: &mut C,
+    ) -> Result<ArchiveSummary, String> {
+        let file = File::open(path).map_err(|e| format!("failed to open {path:?}: {e}"))?;
+        let mut archive = Archive {
+            server_id: self.server_id,
+            watermarks: self.watermarks,
+            marks: ChannelMarks::default(),
+            tally: Tally::default(),
+            users: Vec::new(),
+            newest: None,
+            summary: ArchiveSummary {
+                dry_run: self.dry_run,
+                ..ArchiveSummary::default()
+            },
+        };
+        serde_json::Deserializer::from_reader(BufReader::new(file))
+            .deserialize_map(ArchiveVisitor(&mut archive))
+            .map_err(|e| format!("could not import archive {path:?}: {e}"))?;
+        let Archive {
+            marks,
+            tally,
+            newest,
+            mut summary,
+            ..
+        } = archive;
+        let updates = tally.into_updates();
+        summary.updates = updates.len();
+        let channel = summary.channel;
+        if self.dry_run {
+            return Ok(summary);
+        }
+        let mut watermarks = lock(self.watermarks, "watermarks")?;
+        // the archive was checked against the marks without holding the lock, nothing may
+        // have been counted in the channel since.
+        if watermarks.channel_marks(self.server_id, channel) != marks {
+            Err(format!(
+                "channel {channel} was counted into while {path:?} was read, import it again"
+            ))?
+        }
+        watermarks.skip_archived(self.server_id, channel, summary.duplicates);
+        let Some(newest) = newest else {
+            return Ok(summary);
+        };
+        let claimed = watermarks.claim_backfilled(self.server_id, channel, Some(newest));
+        drop(watermarks);
+        let checkpoint = Checkpoint {
+            pointer: Some(newest.timestamp),
+            backfilled: Some(newest),
+            ..Checkpoint::new(self.server_id, channel)
+        };
+        if let Err(e) = database.count(&updates, &checkpoint) {
+            lock(self.watermarks, "watermarks")?.claim_backfilled(self.server_id, channel, claimed);
+            return Err(e);
+        }
+        Ok(summary)
+    }
+}
+
+// the state of an archive while it is streamed.
+struct Archive<'a> {
+    server_id: usize,
+    watermarks: &'a Mutex<Watermarks>,
+    // of the channel, as they were when its messages started.
+    marks: ChannelMarks,
+    tally: Tally,
+    users: Vec<usize>,
+    newest: Option<Mark>,
+    summary: ArchiveSummary,
+}
+impl Archive<'_> {
+    /// the guild and channel are known, every message comes after.
+    fn begin(
+        &mut self,
+        guild: Option<Guild>,
+        channel: Option<Channel>,
+        range: Option<DateRange>,
+    ) -> Result<(), String> {
+        let guild = guild.ok_or("the guild must come before the messages")?;
+        let channel = channel.ok_or("the channel must come before the messages")?;
+        if parse_id(&guild.id)? != self.server_id {
+            Err(format!(
+                "archive is of guild {}, not {}",
+                guild.id, self.server_id
+            ))?
+        }
+        self.summary.channel = parse_id(&channel.id)?;
+        self.summary.channel_name = channel.name;
+        self.marks = lock(self.watermarks, "watermarks")?
+            .channel_marks(self.server_id, self.summary.channel);
+        // everything up to the frontier is counted, an archive starting past it would leave
+        // a gap the backfill frontier jumps over.
+        let frontier = self
+            .marks
+            .backfilled()
+            .map_or(LOUIS_EPOCH.timestamp() as f64, |mark| mark.timestamp);
+        if let Some(after) = range.and_then(|range| range.after) {
+            let after = parse_timestamp(&after)?;
+            if after > frontier {
+                Err(format!(
+                    "archive starts after the messages of channel {} counted so far, \
+                     export it from further back",
+                    self.summary.channel
+                ))?
+            }
+        }
+        Ok(())
+    }
+    fn message(&mut self, message: Message) -> Result<(), String> {
+        let id = parse_id(&message.id)? as u64;
+        let timestamp = parse_timestamp(&message.timestamp)?;
+        let (author_id, author_name) = message.author.parse()?;
+        if timestamp < LOUIS_EPOCH.timestamp() as f64 {
+            self.summary.too_old += 1;
+            return Ok(());
+        }
+        if !self.marks.admits(author_id, id) {
+            self.summary.duplicates += 1;
+            return Ok(());
+        }
+        let mut reactions = Vec::new();
+        for reaction in message.reactions {
+            self.summary.reactions += reaction.count;
+            self.summary.unattributed_reactions +=
+                reaction.count.saturating_sub(reaction.users.len());
+            for user in reaction.users {
+                let (user_id, user_name) = user.parse()?;
+                reactions.push(HistoryReaction {
+                    name: reaction.emoji.name.clone(),
+                    user_id,
+                    user_name,
+                });
+            }
+        }
+        self.tally.add(&HistoryMessage {
+            id,
+            author_id,
+            author_name,
+            timestamp,
+            reactions,
+        });
+        if let Err(i) = self.users.binary_search(&author_id) {
+            self.users.insert(i, author_id);
+        }
+        self.summary.messages += 1;
+        self.summary.users = self.users.len();
+        self.summary.first = self.summary.first.or(Some(timestamp));
+        self.summary.last = Some(timestamp);
+        if self.newest.is_none_or(|newest| id > newest.id) {
+            self.newest = Some(Mark { id, timestamp });
+        }
+        Ok(())
+    }
+}
+
+struct ArchiveVisitor<'s, 'a>(&'s mut Archive<'a>);
+impl<'de> Visitor<'de> for ArchiveVisitor<'_, '_> {
+    type Value = ();
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a DiscordChatExporter archive")
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let (mut guild, mut channel, mut range, mut messages) = (None, None, None, false);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "guild" => guild = Some(map.next_value::<Guild>()?),
+                "channel" => channel = Some(map.next_value::<Channel>()?),
+                "dateRange" => range = Some(map.next_value::<DateRange>()?),
+                "filter" => {
+                    if map.next_value::<Option<IgnoredAny>>()?.is_some() {
+                        Err(serde::de::Error::custom(
+                            "archive was exported with a filter and leaves messages out, \
+                             export the whole channel",
+                        ))?
+                    }
+                }
+                "messages" => {
+                    self.0
+                        .begin(guild.take(), channel.take(), range.take())
+                        .map_err(serde::de::Error::custom)?;
+                    map.next_value_seed(MessagesSeed(&mut *self.0))?;
+                    messages = true;
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        if !messages {
+            Err(serde::de::Error::missing_field("messages"))?
+        }
+        Ok(())
+    }
+}
+
+struct MessagesSeed<'s, 'a>(&'s mut Archive<'a>);
+impl<'de> DeserializeSeed<'de> for MessagesSeed<'_, '_> {
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+impl<'de> Visitor<'de> for MessagesSeed<'_, '_> {
+    type Value = ();
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of messages")
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(message) = seq.next_element::<Message>()? {
+            self.0.message(message).map_err(serde::de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<f64, String> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|t| t.timestamp_millis() as f64 / 1000.0)
+        .map_err(|e| format!("invalid timestamp \"{timestamp}\": {e}"))
+}
+
+fn parse_id(id: &str) -> Result<usize, String> {
+    id.parse().map_err(|e| format!("invalid id \"{id}\": {e}"))
+}
+
+fn lock<'m, T>(mutex: &'m Mutex<T>, name: &str) -> Result<MutexGuard<'m, T>, String> {
+    mutex.lock().map_err(|_| format!("{name} lock poisoned"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::database::{
+        backfill::last_id_at, counted, rollups::Totals, test_database, test_dir, watermarks::Marks,
+    };
+
+    const GUILD: usize = 1;
+    const CHANNEL: usize = 10;
+    /// what importing an archive of `archive` counts.
+    const IMPORTED: Totals = Totals {
+        messages: 3,
+        reactions: 1,
+    };
+
+    /// an archive of `CHANNEL` in `guild` with a message an hour after the louis epoch by each
+    /// of three users, the first with a reaction only one user is known for.
+    fn archive(directory: &Path, name: &str, guild: usize, filter: &str) -> PathBuf {
+        let messages: Vec<String> = (1..=3)
+            .map(|n| {
+                let timestamp = LOUIS_EPOCH.timestamp() + n * 3600;
+                let reactions = match n {
+                    1 => r#"[{"emoji":{"id":null,"name":"👍"},"count":2,"users":[{"id":"9","name":"bot","nickname":null}]}]"#,
+                    _ => "[]",
+                };
+                format!(
+                    r#"{{"id":"{}","type":"Default","timestamp":"{}","content":"hi","author":{{"id":"{n}","name":"user{n}","nickname":"User {n}","isBot":false}},"reactions":{reactions}}}"#,
+                    last_id_at(timestamp as f64),
+                    DateTime::from_timestamp(timestamp, 0).unwrap().to_rfc3339(),
+                )
+            })
+            .collect();
+        let path = directory.join(name);
+        fs::write(
+            &path,
+            format!(
+                r#"{{"guild":{{"id":"{guild}","name":"louis"}},"channel":{{"id":"{CHANNEL}","type":"GuildTextChat","name":"general"}},"dateRange":{{"after":null,"before":null}},"filter":{filter},"exportedAt":"2025-06-01T00:00:00+00:00","messages":[{}],"messageCount":3}}"#,
+                messages.join(",")
+            ),
+        )
+        .unwrap();
+        path
+    }
+
+    #[test]
+    fn a_dry_run_summarizes_what_the_import_counts() {
+        let directory = test_dir("chat-export");
+        let path = archive(&directory, "general.json", GUILD, "null");
+        let marks = Marks::open(&directory).unwrap();
+        let mut database = test_database(&directory, &marks);
+        let mut importer = ChatExportImporter::new(GUILD, &marks.watermarks);
+        importer.set_dry_run(true);
+        let dry = importer.import(&path, &mut database).unwrap();
+        assert_eq!(counted(&database), Totals::default());
+        assert_eq!(marks.watermarks().unwrap().backfilled(GUILD, CHANNEL), None);
+        importer.set_dry_run(false);
+        let summary = importer.import(&path, &mut database).unwrap();
+        for summary in [&dry, &summary] {
+            assert_eq!(
+                (summary.channel, summary.channel_name.as_str()),
+                (CHANNEL, "general")
+            );
+            assert_eq!(
+                (summary.messages, summary.users, summary.updates),
+                (3, 3, 4)
+            );
+            assert_eq!((summary.reactions, summary.unattributed_reactions), (2, 1));
+        }
+        assert!(dry.dry_run && !summary.dry_run);
+        assert_eq!(counted(&database), IMPORTED);
+        // importing it again, or a dry run of it, finds only duplicates.
+        importer.set_dry_run(true);
+        let again = importer.import(&path, &mut database).unwrap();
+        assert_eq!((again.messages, again.duplicates), (0, 3));
+        importer.set_dry_run(false);
+        let again = importer.import(&path, &mut database).unwrap();
+        assert_eq!((again.messages, again.duplicates), (0, 3));
+        assert_eq!(counted(&database), IMPORTED);
+        // the process dies before anything is flushed, the marks come back with the counts.
+        drop(database);
+        let marks = Marks::open(&directory).unwrap();
+        let mut database = test_database(&directory, &marks);
+        assert_eq!(counted(&database), IMPORTED);
+        let last = summary.last.unwrap();
+        assert_eq!(marks.cache().unwrap().pointer(GUILD, CHANNEL), Some(last));
+        let importer = ChatExportImporter::new(GUILD, &marks.watermarks);
+        let again = importer.import(&path, &mut database).unwrap();
+        assert_eq!((again.messages, again.duplicates), (0, 3));
+    }
+
+    #[test]
+    fn refuses_archives_of_another_guild_or_exported_with_a_filter() {
+        let directory = test_dir("chat-export-refused");
+        let marks = Marks::open(&directory).unwrap();
+        let mut database = test_database(&directory, &marks);
+        let importer = ChatExportImporter::new(GUILD, &marks.watermarks);
+        let other = archive(&directory, "other.json", 2, "null");
+        let error = importer.import(&other, &mut database).unwrap_err();
+        assert!(error.contains("guild 2"), "{error}");
+        let filtered = archive(&directory, "filtered.json", GUILD, r#""from:louis""#);
+        let error = importer.import(&filtered, &mut database).unwrap_err();
+        assert!(error.contains("filter"), "{error}");
+        assert_eq!(counted(&database), Totals::default());
+        assert_eq!(marks.watermarks().unwrap().backfilled(GUILD, CHANNEL), None);
+    }
+}
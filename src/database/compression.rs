@@ -0,0 +1,173 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{Datelike, Utc};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+
+use crate::database::{
+    cache::ShardCache, lock::DirectoryLock, persist::write_atomic, server::ServerFile,
+};
+
+const ZSTD_LEVEL: i32 = 19;
+
+/// how a year file is stored on disk, told apart by its extension.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec {
+    /// `<server>_<year>.json`, what louisbot4 reads.
+    Plain,
+    /// `<server>_<year>.json.gz`
+    Gzip,
+    /// `<server>_<year>.json.zst`
+    Zstd,
+}
+impl Codec {
+    /// in order of preference when a year is stored more than once, e.g. after an interrupted compression.
+    pub const ALL: [Codec; 3] = [Codec::Plain, Codec::Gzip, Codec::Zstd];
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Plain => ".json",
+            Codec::Gzip => ".json.gz",
+            Codec::Zstd => ".json.zst",
+        }
+    }
+    /// split a file name into what comes before the extension and the codec it implies.
+    pub fn strip(name: &str) -> Option<(&str, Codec)> {
+        // the plain extension is a suffix of neither compressed one, order does not matter.
+        Self::ALL
+            .iter()
+            .find_map(|codec| Some((name.strip_suffix(codec.extension())?, *codec)))
+    }
+    pub fn of(path: &Path) -> Codec {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(Self::strip)
+            .map_or(Codec::Plain, |(_, codec)| codec)
+    }
+    pub fn reader(self, file: File) -> Result<Box<dyn Read>, String> {
+        Ok(match self {
+            Codec::Plain => Box::new(file),
+            Codec::Gzip => Box::new(GzDecoder::new(file)),
+            Codec::Zstd => Box::new(
+                zstd::Decoder::new(file)
+                    .map_err(|e| format!("could not start zstd decoder: {e}"))?,
+            ),
+        })
+    }
+    pub fn encode(self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::Plain => Ok(data),
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+                encoder
+                    .write_all(&data)
+                    .and_then(|_| encoder.finish())
+                    .map_err(|e| format!("could not gzip: {e}"))
+            }
+            Codec::Zstd => zstd::encode_all(data.as_slice(), ZSTD_LEVEL)
+                .map_err(|e| format!("could not zstd: {e}")),
+        }
+    }
+}
+
+/// compress every plain year file of `server_name` older than the current year with `codec`,
+/// returns the compressed years. the bytes are kept as they are, only their encoding changes,
+/// so the journal batches a year is known to hold do not change either.
+/// fails if the server is open for writing.
+pub fn compress_old_years(
+    directory: &Path,
+    server_name: &str,
+    codec: Codec,
+) -> Result<Vec<usize>, String> {
+    if codec == Codec::Plain {
+        Err("year files are already plain json".to_string())?
+    }
+    let _lock = DirectoryLock::exclusive(&directory.join(server_name))?;
+    let current_year = Utc::now().year() as usize;
+    let mut compressed = Vec::new();
+    for (year, path) in ServerFile::year_files(directory, server_name)? {
+        if year >= current_year || Codec::of(&path) != Codec::Plain {
+            continue;
+        }
+        let data = fs::read(&path).map_err(|e| format!("could not read {path:?}: {e}"))?;
+        write_atomic(&with_codec(&path, codec), &codec.encode(data)?)?;
+        // until the plain file is gone it takes precedence, a crash here loses nothing.
+        fs::remove_file(&path).map_err(|e| format!("could not remove {path:?}: {e}"))?;
+        compressed.push(year);
+    }
+    // a cached year would still point at the plain file.
+    for cache in [ShardCache::shared(), ShardCache::read_only()] {
+        cache
+            .lock()
+            .map_err(|_| "shard cache lock poisoned".to_string())?
+            .remove_where(&directory.join(server_name), |year, _| {
+                compressed.contains(&year)
+            });
+    }
+    Ok(compressed)
+}
+
+/// write every year of `server_name` as plain louisbot4 json into `target`, whatever its codec.
+pub fn export_plain(
+    directory: &Path,
+    server_name: &str,
+    target: &Path,
+) -> Result<Vec<usize>, String> {
+    let _lock = DirectoryLock::exclusive(&target.join(server_name))?;
+    let mut exported = Vec::new();
+    for (year, path) in ServerFile::year_files(directory, server_name)? {
+        let file = ServerFile::load(&path, true)?;
+        ServerFile::from_parts(
+            &ServerFile::file_path_in(target, server_name, year),
+            file.get_all_users().into_iter().cloned().collect(),
+            file.get_all_reactions().to_vec(),
+            file.first_day(),
+            file.last_day(),
+        )
+        .flush()?;
+        exported.push(year);
+    }
+    Ok(exported)
+}
+
+/// `path` with its extension swapped for the one of `codec`.
+pub fn with_codec(path: &Path, codec: Codec) -> PathBuf {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let stem = Codec::strip(name).map_or(name, |(stem, _)| stem);
+    path.with_file_name(format!("{stem}{}", codec.extension()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::database::{epoch::LouisEpoch, server::ServerDatabase, test_dir};
+
+    #[test]
+    fn compressed_years_keep_the_batches_they_hold() {
+        let directory = test_dir("compress");
+        let path = directory.join("server");
+        let date = DateTime::from_timestamp(1_750_000_000, 0).unwrap();
+        let mut database = ServerDatabase::new(&path).unwrap();
+        database
+            .update_users(&[(1, "louis", 2, &[], date)])
+            .unwrap();
+        // closed without flushing, the year is written but the journal keeps its batch.
+        drop(database);
+        assert_eq!(
+            compress_old_years(&directory, "server", Codec::Zstd).unwrap(),
+            [2025]
+        );
+        let year = ServerFile::find_year_file(&directory, "server", 2025).unwrap();
+        assert_eq!(Codec::of(&year), Codec::Zstd);
+        let database = ServerDatabase::new(&path).unwrap();
+        let users = database.collect(0, LouisEpoch::MAX).unwrap().users;
+        assert_eq!(users[0].days().values().next().unwrap().total(), 2);
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+
+use crate::database::{
+    backfill::Counter,
+    epoch::LOUIS_EPOCH,
+    server::OwnedUserUpdate,
+    watermarks::{Checkpoint, Mark, Watermarks},
+};
+
+#[derive(Deserialize)]
+struct Account {
+    id: String,
+    username: String,
+    global_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChannelMeta {
+    id: String,
+    name: Option<String>,
+    guild: Option<Guild>,
+}
+
+#[derive(Deserialize)]
+struct Guild {
+    id: String,
+}
+
+// the other columns, `Contents` and `Attachments`, are never read.
+#[derive(Deserialize)]
+struct Row {
+    #[serde(rename = "ID")]
+    id: u64,
+    #[serde(rename = "Timestamp")]
+    timestamp: String,
+}
+
+/// what importing one channel of a `DataPackage` counted.
+#[derive(Default, Debug)]
+pub struct ChannelImport {
+    pub channel: usize,
+    pub name: String,
+    pub messages: usize,
+    /// messages which were already counted, see `Watermarks::admit_import`.
+    pub duplicates: usize,
+    /// messages sent before the louis epoch, there are no days to hold them.
+    pub too_old: usize,
+}
+
+/// the personal data package discord hands out on "request all of my data", unzipped.
+/// it holds every message its owner sent, under `messages/c<channel id>/` along with a
+/// `channel.json` saying which guild the channel belongs to.
+pub struct DataPackage {
+    directory: PathBuf,
+    author_id: usize,
+    author_name: String,
+}
+impl DataPackage {
+    pub fn open(directory: &Path) -> Result<Self, String> {
+        let account: Account = read_json(&directory.join("account").join("user.json"))?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            author_id: parse_id(&account.id)?,
+            author_name: account.global_name.unwrap_or(account.username),
+        })
+    }
+    pub fn author_id(&self) -> usize {
+        self.author_id
+    }
+    /// the id, name and directory of every channel of `guild_id` the owner wrote in, by id.
+    pub fn channels(&self, guild_id: usize) -> Result<Vec<(usize, String, PathBuf)>, String> {
+        let messages = self.directory.join("messages");
+        let mut channels = Vec::new();
+        for entry in fs::read_dir(&messages)
+            .map_err(|e| format!("could not read directory {messages:?}: {e}"))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+        {
+            let meta: ChannelMeta = read_json(&entry.path().join("channel.json"))?;
+            // direct messages and group chats have no guild.
+            let Some(guild) = meta.guild else {
+                continue;
+            };
+            if parse_id(&guild.id)? == guild_id {
+                channels.push((
+                    parse_id(&meta.id)?,
+                    meta.name.unwrap_or_default(),
+                    entry.path(),
+                ));
+            }
+        }
+        channels.sort_by_key(|(id, _, _)| *id);
+        Ok(channels)
+    }
+    /// count the messages of the owner in every channel of `guild_id` into `database`, per hour.
+    /// messages counted live, by a backfill or by importing this package before are skipped,
+    /// each channel is counted along with a `Checkpoint` marking it as imported for its owner.
+    /// `watermarks` have to be the ones `database` applies its checkpoints to.
+    pub fn import<C: Counter>(
+        &self,
+        guild_id: usize,
+        database: &mut C,
+        watermarks: &Mutex<Watermarks>,
+    ) -> Result<Vec<ChannelImport>, String> {
+        let mut imported = Vec::new();
+        for (channel, name, directory) in self.channels(guild_id)? {
+            let mut import = ChannelImport {
+                channel,
+                name,
+                ..ChannelImport::default()
+            };
+            let messages = read_messages(&directory.join("messages.csv"))?;
+            let mut hours: BTreeMap<i64, usize> = BTreeMap::new();
+            // the messages are checked and claimed under one lock, see `Backfill::step`.
+            let mut marks = lock(watermarks)?;
+            let mut newest: Option<Mark> = None;
+            for (id, sent) in messages {
+                if sent < *LOUIS_EPOCH {
+                    import.too_old += 1;
+                } else if !marks.admit_import(guild_id, channel, self.author_id, id) {
+                    import.duplicates += 1;
+                } else {
+                    *hours
+                        .entry(sent.timestamp().div_euclid(3600) * 3600)
+                        .or_default() += 1;
+                    import.messages += 1;
+                    if newest.is_none_or(|n| id > n.id) {
+                        newest = Some(Mark {
+                            id,
+                            timestamp: sent.timestamp_millis() as f64 / 1000.0,
+                        });
+                    }
+                }
+            }
+            marks.skip_imported(guild_id, channel, import.duplicates);
+            let Some(newest) = newest else {
+                imported.push(import);
+                continue;
+            };
+            let claimed = marks.claim_imported(guild_id, channel, self.author_id, Some(newest));
+            drop(marks);
+            let updates: Vec<OwnedUserUpdate> = hours
+                .into_iter()
+                .map(|(hour, messages)| OwnedUserUpdate {
+                    id: self.author_id,
+                    name: self.author_name.clone(),
+                    messages,
+                    reactions: Vec::new(),
+                    timestamp: hour,
+                })
+                .collect();
+            let checkpoint = Checkpoint {
+                imported: Some((self.author_id, newest)),
+                ..Checkpoint::new(guild_id, channel)
+            };
+            if let Err(e) = database.count(&updates, &checkpoint) {
+                lock(watermarks)?.claim_imported(guild_id, channel, self.author_id, claimed);
+                return Err(e);
+            }
+            imported.push(import);
+        }
+        Ok(imported)
+    }
+}
+
+/// the id and time of every message in a `messages.csv`, in file order.
+fn read_messages(path: &Path) -> Result<Vec<(u64, DateTime<Utc>)>, String> {
+    let mut reader =
+        csv::Reader::from_path(path).map_err(|e| format!("failed to open {path:?}: {e}"))?;
+    reader
+        .deserialize::<Row>()
+        .map(|row| {
+            let row = row.map_err(|e| format!("could not read {path:?}: {e}"))?;
+            Ok((row.id, parse_timestamp(&row.timestamp)?))
+        })
+        .collect()
+}
+
+/// older packages write "2021-03-15 12:34:56.789000+00:00", newer ones rfc 3339.
+fn parse_timestamp(timestamp: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .or_else(|_| DateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f%:z"))
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("invalid timestamp \"{timestamp}\": {e}"))
+}
+
+fn lock(watermarks: &Mutex<Watermarks>) -> Result<MutexGuard<'_, Watermarks>, String> {
+    watermarks
+        .lock()
+        .map_err(|_| "watermarks lock poisoned".to_string())
+}
+
+fn parse_id(id: &str) -> Result<usize, String> {
+    id.parse().map_err(|e| format!("invalid id \"{id}\": {e}"))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let file = File::open(path).map_err(|e| format!("failed to open {path:?}: {e}"))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("could not read {path:?}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        backfill::last_id_at, counted, test_database, test_dir, watermarks::Marks,
+    };
+
+    const GUILD: usize = 1;
+    const OWNER: usize = 7;
+
+    /// a package of `OWNER` with a message a day after the louis epoch in channels 10 and 11 of
+    /// `GUILD`, in channel 20 of another guild and in a direct message.
+    fn package(directory: &Path) {
+        let write = |path: PathBuf, contents: &str| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        write(
+            directory.join("account").join("user.json"),
+            &format!("{{\"id\":\"{OWNER}\",\"username\":\"louis\",\"global_name\":null}}"),
+        );
+        for (channel, guild) in [
+            (10, Some(GUILD)),
+            (11, Some(GUILD)),
+            (20, Some(2)),
+            (30, None),
+        ] {
+            let guild = guild.map_or("null".to_string(), |id| format!("{{\"id\":\"{id}\"}}"));
+            let channel_directory = directory.join("messages").join(format!("c{channel}"));
+            write(
+                channel_directory.join("channel.json"),
+                &format!("{{\"id\":\"{channel}\",\"name\":\"general\",\"guild\":{guild}}}"),
+            );
+            let mut csv = "ID,Timestamp,Contents,Attachments\n".to_string();
+            // one from before the epoch, two in the old timestamp format and one in the new.
+            for (day, format) in [
+                (-1, "%Y-%m-%d %H:%M:%S%.6f%:z"),
+                (1, "%Y-%m-%d %H:%M:%S%.6f%:z"),
+                (2, "%Y-%m-%d %H:%M:%S%.6f%:z"),
+                (3, "%+"),
+            ] {
+                let sent = *LOUIS_EPOCH + chrono::TimeDelta::days(day);
+                let id = last_id_at(sent.timestamp() as f64);
+                csv += &format!("{id},{},hello,\n", sent.format(format));
+            }
+            write(channel_directory.join("messages.csv"), &csv);
+        }
+    }
+
+    #[test]
+    fn counts_the_owner_in_the_channels_of_the_guild() {
+        let directory = test_dir("data-package");
+        package(&directory);
+        let package = DataPackage::open(&directory).unwrap();
+        let channels: Vec<usize> = package
+            .channels(GUILD)
+            .unwrap()
+            .iter()
+            .map(|(id, _, _)| *id)
+            .collect();
+        assert_eq!(channels, [10, 11]);
+        let marks = Marks::open(&directory).unwrap();
+        let mut database = test_database(&directory, &marks);
+        let imported = package
+            .import(GUILD, &mut database, &marks.watermarks)
+            .unwrap();
+        assert_eq!(
+            imported
+                .iter()
+                .map(|i| (i.channel, i.messages, i.too_old))
+                .collect::<Vec<_>>(),
+            [(10, 3, 1), (11, 3, 1)]
+        );
+        let leaderboard = database.rollups().leaderboard(None, None);
+        assert_eq!(
+            (leaderboard[0].id, leaderboard[0].totals.messages),
+            (OWNER as u64, 6)
+        );
+        // importing the same package again counts nothing.
+        let imported = package
+            .import(GUILD, &mut database, &marks.watermarks)
+            .unwrap();
+        assert_eq!(imported.iter().map(|i| i.duplicates).sum::<usize>(), 6);
+        assert_eq!(counted(&database).messages, 6);
+    }
+
+    #[test]
+    fn skips_what_a_backfill_counted_and_survives_a_crash() {
+        let directory = test_dir("data-package-backfilled");
+        package(&directory);
+        let package = DataPackage::open(&directory).unwrap();
+        let marks = Marks::open(&directory).unwrap();
+        let mut database = test_database(&directory, &marks);
+        // a backfill read channel 10 up to the second day.
+        let second_day = LOUIS_EPOCH.timestamp() as f64 + 2.0 * 86_400.0;
+        let checkpoint = Checkpoint {
+            backfilled: Some(Mark {
+                id: last_id_at(second_day),
+                timestamp: second_day,
+            }),
+            ..Checkpoint::new(GUILD, 10)
+        };
+        database
+            .update_users_checkpointed(&[], Some(&checkpoint))
+            .unwrap();
+        let imported = package
+            .import(GUILD, &mut database, &marks.watermarks)
+            .unwrap();
+        assert_eq!((imported[0].messages, imported[0].duplicates), (1, 2));
+        assert_eq!(counted(&database).messages, 4);
+        // the process dies before anything is flushed.
+        drop(database);
+        let marks = Marks::open(&directory).unwrap();
+        let mut database = test_database(&directory, &marks);
+        assert_eq!(counted(&database).messages, 4);
+        let imported = package
+            .import(GUILD, &mut database, &marks.watermarks)
+            .unwrap();
+        assert_eq!(imported.iter().map(|i| i.messages).sum::<usize>(), 0);
+        assert_eq!(counted(&database).messages, 4);
+    }
+}
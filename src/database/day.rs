@@ -1,22 +1,140 @@
-use std::{collections::HashMap, iter::repeat};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
 
-use serde_derive::{Deserialize, Serialize};
+use serde::ser::{SerializeMap, SerializeStruct};
+use serde_derive::Deserialize;
 
 use crate::database::epoch::{LouisEpoch, epoch_to_unix, now};
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Day {
+pub const HOURS: usize = 24;
+/// one count per hour of the day.
+pub type Hours = [u32; HOURS];
+/// a reaction name, shared by every day of a `ServerFile` which counts it, see `Interner`.
+pub type Reaction = Arc<str>;
+
+/// hands out one shared `Reaction` per name.
+#[derive(Default, Clone)]
+pub struct Interner {
+    names: HashSet<Reaction>,
+}
+impl Interner {
+    pub fn intern(&mut self, name: &str) -> Reaction {
+        match self.names.get(name) {
+            Some(reaction) => reaction.clone(),
+            None => {
+                let reaction = Reaction::from(name);
+                self.names.insert(reaction.clone());
+                reaction
+            }
+        }
+    }
+}
+
+// the layout louisbot4 writes, hour vectors of any other length than `HOURS` are kept as
+// they are, see `Irregular`.
+#[derive(Deserialize)]
+struct DayInit {
     date: f64,
     msg_hours: Vec<u64>,
-    emoji_hours: HashMap<String, Vec<u64>>,
+    emoji_hours: BTreeMap<String, Vec<u64>>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(from = "DayInit")]
+pub struct Day {
+    date: f64,
+    msg_hours: Hours,
+    // sorted by name, serialized as the `emoji_hours` map of louisbot4.
+    emoji_hours: Vec<(Reaction, Hours)>,
+    // only set for days loaded with hour vectors of the wrong length, which is rare.
+    irregular: Option<Box<Irregular>>,
+}
+/// the length an hour vector was loaded with and the counts it held past the last hour,
+/// which are not counted anywhere until `fsck --repair` folds them into the last hour.
+#[derive(Clone)]
+struct Shape {
+    len: usize,
+    past_last_hour: Vec<u32>,
+}
+#[derive(Clone, Default)]
+struct Irregular {
+    msg_hours: Option<Shape>,
+    emoji_hours: BTreeMap<Reaction, Shape>,
+}
+impl From<DayInit> for Day {
+    fn from(init: DayInit) -> Self {
+        let mut irregular = Irregular::default();
+        let (msg_hours, shape) = split(&init.msg_hours);
+        irregular.msg_hours = shape;
+        let mut emoji_hours = Vec::with_capacity(init.emoji_hours.len());
+        for (name, hours) in init.emoji_hours {
+            let name = Reaction::from(name);
+            let (hours, shape) = split(&hours);
+            if let Some(shape) = shape {
+                irregular.emoji_hours.insert(name.clone(), shape);
+            }
+            emoji_hours.push((name, hours));
+        }
+        let irregular = (irregular.msg_hours.is_some() || !irregular.emoji_hours.is_empty())
+            .then(|| Box::new(irregular));
+        Self {
+            date: init.date,
+            msg_hours,
+            emoji_hours,
+            irregular,
+        }
+    }
+}
+impl serde::Serialize for Day {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let irregular = self.irregular.as_deref();
+        let mut day = serializer.serialize_struct("Day", 3)?;
+        day.serialize_field("date", &self.date)?;
+        day.serialize_field(
+            "msg_hours",
+            &Raw(
+                &self.msg_hours,
+                irregular.and_then(|i| i.msg_hours.as_ref()),
+            ),
+        )?;
+        day.serialize_field("emoji_hours", &EmojiHours(&self.emoji_hours, irregular))?;
+        day.end()
+    }
+}
+struct EmojiHours<'a>(&'a [(Reaction, Hours)], Option<&'a Irregular>);
+impl serde::Serialize for EmojiHours<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, hours) in self.0 {
+            let shape = self.1.and_then(|i| i.emoji_hours.get(name));
+            map.serialize_entry(&**name, &Raw(hours, shape))?;
+        }
+        map.end()
+    }
+}
+// an hour vector written back with the shape it was loaded with.
+struct Raw<'a>(&'a Hours, Option<&'a Shape>);
+impl serde::Serialize for Raw<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(shape) = self.1 else {
+            return serde::Serialize::serialize(self.0, serializer);
+        };
+        // a short vector grows as far as counts were added past its end.
+        let counted = self.0.iter().rposition(|h| *h > 0).map_or(0, |h| h + 1);
+        let len = shape.len.min(HOURS).max(counted);
+        serializer.collect_seq(self.0[..len].iter().chain(&shape.past_last_hour))
+    }
 }
 
 impl Day {
     pub fn new(date: f64) -> Self {
         Self {
             date,
-            msg_hours: generate_empty_hours(),
-            emoji_hours: HashMap::new(),
+            msg_hours: [0; HOURS],
+            emoji_hours: Vec::new(),
+            irregular: None,
         }
     }
     pub fn new_with_epoch(epoch: LouisEpoch) -> Self {
@@ -29,52 +147,166 @@ impl Day {
     pub fn new_from_timeof(t: LouisEpoch) -> Self {
         Self::new(epoch_to_unix(t).timestamp() as f64)
     }
+    pub fn date(&self) -> f64 {
+        self.date
+    }
+    pub fn hours(&self) -> &Hours {
+        &self.msg_hours
+    }
+    /// every reaction counted on this day, sorted by name.
+    pub fn reactions(&self) -> impl Iterator<Item = (&str, &Hours)> {
+        self.emoji_hours
+            .iter()
+            .map(|(name, hours)| (&**name, hours))
+    }
     pub fn increment(&mut self, hour: usize, value: usize) {
-        if hour > self.msg_hours.len() {
-            panic!(
-                "{hour} out of range, {hour} > {} = false",
-                self.msg_hours.len()
-            )
+        if hour >= HOURS {
+            panic!("Hour invalid {hour} > 23")
         }
-        self.msg_hours[hour] += value as u64;
+        add(&mut self.msg_hours[hour], value);
     }
     pub fn total(&self) -> usize {
-        self.msg_hours.iter().sum::<u64>() as usize
+        total(&self.msg_hours)
     }
     pub fn total_reactions_of(&self, reaction: &str) -> usize {
-        self.emoji_hours
-            .get(reaction)
-            .map(|reactions| reactions.iter().sum::<u64>() as usize)
-            .unwrap_or(0)
+        self.reaction_hours(reaction).map(total).unwrap_or(0)
     }
-    pub fn increment_reaction(&mut self, reaction: &str, hour: usize, count: usize) {
-        if hour > 23 {
+    pub fn increment_reaction(&mut self, reaction: &Reaction, hour: usize, count: usize) {
+        if hour >= HOURS {
             panic!("Hour invalid {hour} > 23")
         }
-        if let Some(r) = self.emoji_hours.get_mut(reaction) {
-            r[hour] += count as u64;
-        } else {
-            self.emoji_hours
-                .insert(reaction.to_string(), generate_empty_hours());
-            self.emoji_hours.get_mut(reaction).unwrap()[hour] += count as u64;
+        add(&mut self.reaction_hours_mut(reaction)[hour], count);
+    }
+    /// rough number of bytes this day occupies in memory, reaction names belong to the `Interner`.
+    pub fn estimated_size(&self) -> usize {
+        size_of::<Self>()
+            + self.emoji_hours.capacity() * size_of::<(Reaction, Hours)>()
+            + self
+                .irregular
+                .as_ref()
+                .map_or(0, |_| size_of::<Irregular>())
+    }
+    /// share the reaction names of this day with every other day using `interner`.
+    pub fn intern_reactions(&mut self, interner: &mut Interner) {
+        for (name, _) in self.emoji_hours.iter_mut() {
+            *name = interner.intern(name);
+        }
+    }
+    /// fold the counts of hour vectors loaded past the last hour into it, so the day is written
+    /// back with `HOURS` counts everywhere.
+    pub fn repair(&mut self) {
+        let Some(irregular) = self.irregular.take() else {
+            return;
+        };
+        if let Some(shape) = irregular.msg_hours {
+            fold(&mut self.msg_hours, &shape);
+        }
+        for (reaction, shape) in irregular.emoji_hours {
+            fold(self.reaction_hours_mut(&reaction), &shape);
+        }
+    }
+    /// add every hourly count of `other` onto this day.
+    pub fn merge(&mut self, other: &Day) {
+        add_hours(&mut self.msg_hours, &other.msg_hours);
+        for (reaction, hours) in &other.emoji_hours {
+            add_hours(self.reaction_hours_mut(reaction), hours);
         }
     }
-    fn get_reaction(&self, reaction: &str) -> Vec<u64> {
+    fn reaction_hours(&self, reaction: &str) -> Option<&Hours> {
         self.emoji_hours
-            .get(reaction)
-            .map(|a| a.to_owned())
-            .unwrap_or(generate_empty_hours())
+            .binary_search_by(|(name, _)| (**name).cmp(reaction))
+            .ok()
+            .map(|i| &self.emoji_hours[i].1)
+    }
+    fn reaction_hours_mut(&mut self, reaction: &Reaction) -> &mut Hours {
+        let i = match self
+            .emoji_hours
+            .binary_search_by(|(name, _)| name.cmp(reaction))
+        {
+            Ok(i) => i,
+            Err(i) => {
+                self.emoji_hours.insert(i, (reaction.clone(), [0; HOURS]));
+                i
+            }
+        };
+        &mut self.emoji_hours[i].1
+    }
+    fn get_reaction(&self, reaction: &str) -> Hours {
+        self.reaction_hours(reaction).copied().unwrap_or([0; HOURS])
     }
     fn avg_hours(&self) -> f64 {
-        self.msg_hours.iter().sum::<u64>() as f64 / self.msg_hours.len() as f64
+        self.total() as f64 / HOURS as f64
     }
     fn avg_reactions_of(&self, reaction: &str) -> f64 {
-        self.emoji_hours
-            .get(reaction)
-            .map(|h| h.iter().sum::<u64>() as f64 / h.len() as f64)
-            .unwrap_or(0.0)
+        self.total_reactions_of(reaction) as f64 / HOURS as f64
     }
 }
-fn generate_empty_hours() -> Vec<u64> {
-    repeat(0).take(24).collect()
+fn add(count: &mut u32, value: usize) {
+    *count = count.saturating_add(u32::try_from(value).unwrap_or(u32::MAX));
+}
+fn total(hours: &Hours) -> usize {
+    hours.iter().map(|h| *h as usize).sum()
+}
+fn add_hours(hours: &mut Hours, other: &Hours) {
+    hours
+        .iter_mut()
+        .zip(other)
+        .for_each(|(h, o)| *h = h.saturating_add(*o));
+}
+/// the first `HOURS` counts of `hours`, and its shape if it has any other length.
+fn split(hours: &[u64]) -> (Hours, Option<Shape>) {
+    let count = |h: &u64| u32::try_from(*h).unwrap_or(u32::MAX);
+    let mut split = [0; HOURS];
+    for (hour, h) in split.iter_mut().zip(hours) {
+        *hour = count(h);
+    }
+    let shape = (hours.len() != HOURS).then(|| Shape {
+        len: hours.len(),
+        past_last_hour: hours.iter().skip(HOURS).map(count).collect(),
+    });
+    (split, shape)
+}
+fn fold(hours: &mut Hours, shape: &Shape) {
+    for count in &shape.past_last_hour {
+        hours[HOURS - 1] = hours[HOURS - 1].saturating_add(*count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_saturate_but_totals_do_not() {
+        let mut day = Day::new(0.0);
+        day.increment(3, u32::MAX as usize);
+        day.increment(3, 2);
+        assert_eq!(day.hours()[3], u32::MAX);
+        day.increment(4, u32::MAX as usize);
+        assert_eq!(day.total(), 2 * u32::MAX as usize);
+        let mut merged = Day::new(0.0);
+        merged.merge(&day);
+        merged.merge(&day);
+        assert_eq!(merged.hours()[3], u32::MAX);
+    }
+
+    #[test]
+    fn irregular_hour_vectors_are_written_back_as_loaded() {
+        let raw = r#"{"date":0.0,"msg_hours":[1,2,3],"emoji_hours":{"a":[5,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,1,7,2]}}"#;
+        let day: Day = serde_json::from_str(raw).unwrap();
+        assert_eq!(day.total(), 6);
+        assert_eq!(day.total_reactions_of("a"), 6);
+        assert_eq!(serde_json::to_string(&day).unwrap(), raw);
+    }
+
+    #[test]
+    fn repair_folds_counts_past_the_last_hour() {
+        let raw = r#"{"date":0.0,"msg_hours":[1,2,3],"emoji_hours":{"a":[5,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,1,7,2]}}"#;
+        let mut day: Day = serde_json::from_str(raw).unwrap();
+        day.repair();
+        assert_eq!(day.total_reactions_of("a"), 15);
+        let written: serde_json::Value = serde_json::to_value(&day).unwrap();
+        assert_eq!(written["msg_hours"].as_array().unwrap().len(), HOURS);
+        assert_eq!(written["emoji_hours"]["a"][HOURS - 1], 10);
+    }
 }
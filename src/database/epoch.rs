@@ -33,3 +33,34 @@ pub fn unix_to_epoch(u: &UnixEpoch) -> LouisEpoch {
         0
     }
 }
+
+/// first and last day of `year`, `None` for years which ended before the epoch
+/// or lie beyond what a date can hold, e.g. from a stray file name.
+/// the year the epoch falls in starts on day 0.
+pub fn year_bounds(year: usize) -> Option<(LouisEpoch, LouisEpoch)> {
+    let year = i32::try_from(year).ok()?;
+    let last = Utc.with_ymd_and_hms(year, 12, 31, 0, 0, 0).single()?;
+    if last < *LOUIS_EPOCH {
+        return None;
+    }
+    let first = Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).single()?;
+    Some((unix_to_epoch(&first), unix_to_epoch(&last)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn years_before_the_epoch_have_no_days() {
+        assert_eq!(year_bounds(2024), None);
+        assert_eq!(year_bounds(2025), Some((0, 231)));
+        assert_eq!(year_bounds(2026), Some((232, 596)));
+    }
+
+    #[test]
+    fn years_beyond_any_date_have_no_days() {
+        assert_eq!(year_bounds(300_000), None);
+        assert_eq!(year_bounds(usize::MAX), None);
+    }
+}
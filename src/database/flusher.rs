@@ -0,0 +1,139 @@
+use std::{future::Future, time::Duration};
+
+use tokio::{signal, time};
+
+use crate::database::{handle::DatabaseRegistry, storage::Storage};
+
+/// flush every server open in `registry` every `interval` until SIGINT or SIGTERM arrives,
+/// then flush them one last time.
+/// a failed periodic flush is reported and retried on the next tick, only the final one is returned.
+pub async fn flush_periodically<S: Storage + Send + Sync + 'static>(
+    registry: DatabaseRegistry<S>,
+    interval: Duration,
+) -> Result<(), String> {
+    flush_until(registry, interval, shutdown_signal()).await
+}
+
+/// `flush_periodically` until `shutdown` resolves.
+async fn flush_until<S: Storage + Send + Sync + 'static>(
+    registry: DatabaseRegistry<S>,
+    interval: Duration,
+    shutdown: impl Future<Output = Result<(), String>>,
+) -> Result<(), String> {
+    let mut ticker = time::interval(interval);
+    // the first tick completes immediately, there is nothing to flush yet.
+    ticker.tick().await;
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                if let Err(e) = registry.flush_all().await {
+                    eprintln!("periodic flush failed: {e}");
+                }
+            }
+            received = &mut shutdown => {
+                received?;
+                break;
+            }
+        }
+    }
+    registry.flush_all().await
+}
+
+/// resolves once the process is asked to stop.
+pub async fn shutdown_signal() -> Result<(), String> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .map_err(|e| format!("could not listen for SIGTERM: {e}"))?;
+        tokio::select! {
+            received = signal::ctrl_c() => received.map_err(|e| format!("could not listen for SIGINT: {e}")),
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c()
+        .await
+        .map_err(|e| format!("could not listen for ctrl-c: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, time::SystemTime};
+
+    use chrono::DateTime;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::database::{
+        server::{OwnedUserUpdate, ServerFile},
+        test_dir,
+    };
+
+    // in 2025 and 2026.
+    const JUNE_2025: i64 = 1_750_000_000;
+    const JUNE_2026: i64 = 1_781_000_000;
+
+    fn update(timestamp: i64) -> Vec<OwnedUserUpdate> {
+        let update = (
+            1,
+            "louis",
+            1,
+            &[][..],
+            DateTime::from_timestamp(timestamp, 0).unwrap(),
+        );
+        vec![OwnedUserUpdate::from_update(&update)]
+    }
+    fn written(directory: &Path, year: usize) -> SystemTime {
+        let path = ServerFile::file_path_in(directory, "server", year);
+        fs::metadata(path).unwrap().modified().unwrap()
+    }
+    // what stops `flush_until`, and the task running it.
+    fn flusher(
+        registry: &DatabaseRegistry,
+        interval: Duration,
+    ) -> (
+        oneshot::Sender<()>,
+        tokio::task::JoinHandle<Result<(), String>>,
+    ) {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let shutdown = async { stopped.await.map_err(|e| e.to_string()) };
+        let task = tokio::spawn(flush_until(registry.clone(), interval, shutdown));
+        (stop, task)
+    }
+
+    #[tokio::test]
+    async fn periodic_flushes_rewrite_only_dirty_years() {
+        let directory = test_dir("flusher-dirty");
+        let registry = DatabaseRegistry::new(&directory);
+        let server = registry.server("server").await.unwrap();
+        server.update_users(update(JUNE_2025)).await.unwrap();
+        server.update_users(update(JUNE_2026)).await.unwrap();
+        server.flush().await.unwrap();
+        let (before_2025, before_2026) = (written(&directory, 2025), written(&directory, 2026));
+        let (stop, task) = flusher(&registry, Duration::from_millis(20));
+        server.update_users(update(JUNE_2026)).await.unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(written(&directory, 2025), before_2025);
+        assert_ne!(written(&directory, 2026), before_2026);
+        stop.send(()).unwrap();
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutting_down_flushes_every_server() {
+        let directory = test_dir("flusher-shutdown");
+        let registry = DatabaseRegistry::new(&directory);
+        let server = registry.server("server").await.unwrap();
+        // no tick comes before the shutdown.
+        let (stop, task) = flusher(&registry, Duration::from_secs(3600));
+        server.update_users(update(JUNE_2025)).await.unwrap();
+        stop.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        let path = ServerFile::file_path_in(&directory, "server", 2025);
+        assert_eq!(
+            ServerFile::load(&path, true).unwrap().get_all_users().len(),
+            1
+        );
+    }
+}
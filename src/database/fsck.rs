@@ -0,0 +1,256 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+use crate::database::{
+    day::HOURS, epoch::LouisEpoch, journal::Journal, lock::DirectoryLock, server::ServerFile,
+};
+
+pub enum Problem {
+    /// the file could not be read at all, nothing else was checked.
+    Unreadable(String),
+    /// an hour vector which does not hold 24 entries, `reaction` is `None` for `msg_hours`.
+    HourCount {
+        user: u64,
+        day: LouisEpoch,
+        reaction: Option<String>,
+        len: usize,
+    },
+    /// `meta` does not cover every day stored in the file.
+    MetaMismatch {
+        recorded: (LouisEpoch, LouisEpoch),
+        stored: (LouisEpoch, LouisEpoch),
+    },
+    /// a user stored under a different id than its own.
+    MismatchedUserKey { key: u64, id: u64 },
+    /// a reaction counted in `emoji_hours` but missing from `reactions`.
+    UnregisteredReaction(String),
+}
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Unreadable(e) => write!(f, "unreadable: {e}"),
+            Problem::HourCount {
+                user,
+                day,
+                reaction: None,
+                len,
+            } => write!(f, "user {user} day {day}: msg_hours has {len} hours"),
+            Problem::HourCount {
+                user,
+                day,
+                reaction: Some(reaction),
+                len,
+            } => write!(
+                f,
+                "user {user} day {day}: reaction {reaction} has {len} hours"
+            ),
+            Problem::MetaMismatch { recorded, stored } => write!(
+                f,
+                "meta covers days {}..={} but days {}..={} are stored",
+                recorded.0, recorded.1, stored.0, stored.1
+            ),
+            Problem::MismatchedUserKey { key, id } => {
+                write!(f, "user {id} is stored under key {key}")
+            }
+            Problem::UnregisteredReaction(name) => {
+                write!(f, "reaction {name} is missing from reactions")
+            }
+        }
+    }
+}
+
+pub struct FileReport {
+    pub year: usize,
+    pub path: PathBuf,
+    pub problems: Vec<Problem>,
+    pub repaired: bool,
+}
+
+/// check every year file of `server_name` in `directory`.
+/// with `repair` set, files with problems are fixed and safely rewritten in place,
+/// which requires that no other process is writing the server and that its journal was replayed.
+pub fn fsck(directory: &Path, server_name: &str, repair: bool) -> Result<Vec<FileReport>, String> {
+    let _lock = match repair {
+        true => Some(DirectoryLock::exclusive(&directory.join(server_name))?),
+        false => None,
+    };
+    let journal = directory
+        .join(server_name)
+        .join(format!("{server_name}.journal"));
+    // a repaired file would be replaced under batches which were never written to it.
+    if repair && Journal::has_batches(&journal)? {
+        Err(format!(
+            "{journal:?} holds batches which were not flushed, open the server to replay them before repairing"
+        ))?
+    }
+    ServerFile::year_files(directory, server_name)?
+        .into_iter()
+        .map(|(year, path)| {
+            let loaded = ServerFile::read_raw(&path).and_then(|raw| {
+                let hour_counts = hour_counts(&raw);
+                let file = match repair {
+                    // loaded again so the rewritten file keeps the journal batches it holds.
+                    true => ServerFile::load(&path, false)?,
+                    false => ServerFile::from_raw(&path, raw, true)?,
+                };
+                Ok((file, hour_counts))
+            });
+            let (mut file, mut problems) = match loaded {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    return Ok(FileReport {
+                        year,
+                        path,
+                        problems: vec![Problem::Unreadable(e)],
+                        repaired: false,
+                    });
+                }
+            };
+            problems.extend(file.check());
+            let repaired = repair && !problems.is_empty();
+            if repaired {
+                file.repair();
+                file.flush()?;
+            }
+            Ok(FileReport {
+                year,
+                path,
+                problems,
+                repaired,
+            })
+        })
+        .collect()
+}
+
+// hour vectors are resized while loading, so their length is only visible in the raw file.
+fn hour_counts(raw: &Value) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let Some(users) = raw.get("users").and_then(Value::as_object) else {
+        return problems;
+    };
+    for (key, user) in users {
+        let Some(days) = user.get("days").and_then(Value::as_object) else {
+            continue;
+        };
+        for (day, d) in days {
+            let (Ok(user), Ok(day)) = (key.parse(), day.parse()) else {
+                continue;
+            };
+            let reactions = d
+                .get("emoji_hours")
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten()
+                .map(|(name, hours)| (Some(name.to_string()), hours));
+            for (reaction, hours) in d
+                .get("msg_hours")
+                .map(|h| (None, h))
+                .into_iter()
+                .chain(reactions)
+            {
+                match hours.as_array().map(Vec::len) {
+                    Some(len) if len != HOURS => problems.push(Problem::HourCount {
+                        user,
+                        day,
+                        reaction,
+                        len,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::database::test_dir;
+
+    // user 2 is stored under key 1, its day 40 is past `meta` and has only 3 hours,
+    // and the reaction it counts was never registered.
+    const BROKEN: &str = r#"{
+        "users": {"1": {"id": 2, "name": "louis", "days": {"40": {
+            "date": 0.0, "msg_hours": [1, 2, 3], "emoji_hours": {"👍": [1]}
+        }}}},
+        "reactions": [],
+        "meta": {"first_day": 0, "last_day": 10}
+    }"#;
+
+    fn broken_server() -> PathBuf {
+        let directory = test_dir("fsck");
+        fs::create_dir_all(directory.join("server")).unwrap();
+        fs::write(ServerFile::file_path_in(&directory, "server", 2025), BROKEN).unwrap();
+        directory
+    }
+
+    #[test]
+    fn reports_every_problem_without_touching_the_file() {
+        let directory = broken_server();
+        let reports = fsck(&directory, "server", false).unwrap();
+        assert_eq!(reports.len(), 1);
+        let problems: Vec<String> = reports[0].problems.iter().map(Problem::to_string).collect();
+        assert_eq!(
+            problems,
+            [
+                "user 1 day 40: msg_hours has 3 hours",
+                "user 1 day 40: reaction 👍 has 1 hours",
+                "user 2 is stored under key 1",
+                "meta covers days 0..=10 but days 40..=40 are stored",
+                "reaction 👍 is missing from reactions",
+            ]
+        );
+        assert!(!reports[0].repaired);
+        let raw = fs::read_to_string(ServerFile::file_path_in(&directory, "server", 2025));
+        assert_eq!(raw.unwrap(), BROKEN);
+    }
+
+    #[test]
+    fn repair_leaves_nothing_to_report() {
+        let directory = broken_server();
+        assert!(fsck(&directory, "server", true).unwrap()[0].repaired);
+        let reports = fsck(&directory, "server", false).unwrap();
+        assert!(reports[0].problems.is_empty());
+        let file =
+            ServerFile::load(&ServerFile::file_path_in(&directory, "server", 2025), true).unwrap();
+        assert_eq!(file.get_all_users()[0].id, 1);
+        assert_eq!(file.get_all_reactions(), ["👍"]);
+        assert_eq!((file.first_day(), file.last_day()), (0, 40));
+    }
+
+    #[test]
+    fn repair_refuses_an_unreplayed_journal() {
+        let directory = broken_server();
+        let journal = directory.join("server").join("server.journal");
+        fs::write(&journal, "{\"seq\":1,\"entries\":[]}\n").unwrap();
+        assert!(fsck(&directory, "server", true).is_err());
+        assert!(
+            !fsck(&directory, "server", false).unwrap()[0]
+                .problems
+                .is_empty()
+        );
+        fs::write(&journal, "").unwrap();
+        assert!(fsck(&directory, "server", true).unwrap()[0].repaired);
+    }
+
+    #[test]
+    fn reports_unreadable_files() {
+        let directory = test_dir("fsck-unreadable");
+        fs::create_dir_all(directory.join("server")).unwrap();
+        fs::write(
+            ServerFile::file_path_in(&directory, "server", 2025),
+            "{\"users\":",
+        )
+        .unwrap();
+        let reports = fsck(&directory, "server", true).unwrap();
+        assert!(matches!(reports[0].problems[..], [Problem::Unreadable(_)]));
+        assert!(!reports[0].repaired);
+    }
+}
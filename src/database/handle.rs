@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use tokio::{sync::Mutex, task};
+
+use crate::database::{
+    epoch::LouisEpoch,
+    rollups::Standing,
+    server::{OwnedUserUpdate, ServerDatabase, ServerFiles},
+    storage::{CollectedRange, Storage},
+    watermarks::{Checkpoint, Mark},
+};
+
+/// a cloneable handle to the database of one server, usable from concurrent tasks.
+/// writes are serialized, reads run alongside each other and only hold up writes while they take
+/// their snapshot, and all file io happens on the blocking thread pool so the async runtime is
+/// never stalled.
+pub struct ServerHandle<S: Storage = ServerFiles> {
+    database: Arc<RwLock<ServerDatabase<S>>>,
+}
+// derived Clone would require `S: Clone`.
+impl<S: Storage> Clone for ServerHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            database: self.database.clone(),
+        }
+    }
+}
+impl<S: Storage + Send + Sync + 'static> ServerHandle<S> {
+    pub fn new(database: ServerDatabase<S>) -> Self {
+        Self {
+            database: Arc::new(RwLock::new(database)),
+        }
+    }
+    pub async fn update_users(&self, data: Vec<OwnedUserUpdate>) -> Result<(), String> {
+        self.write(move |database| database.update_users_owned(&data))
+            .await
+    }
+    /// `ServerDatabase::update_users_checkpointed` for callers already off the async runtime,
+    /// e.g. a backfill thread.
+    pub fn update_users_blocking(
+        &self,
+        data: &[OwnedUserUpdate],
+        checkpoint: &Checkpoint,
+    ) -> Result<(), String> {
+        self.database
+            .write()
+            .map_err(|_| "database lock poisoned".to_string())?
+            .update_users_checkpointed(data, Some(checkpoint))
+    }
+    /// `ServerDatabase::count_live`, returns whether the message was counted.
+    pub async fn count_live(
+        &self,
+        server_id: usize,
+        channel_id: usize,
+        message: Mark,
+        data: Vec<OwnedUserUpdate>,
+    ) -> Result<bool, String> {
+        self.write(move |database| database.count_live(server_id, channel_id, message, &data))
+            .await
+    }
+    /// collects users with their days limited to `start..=end`, other reads may run at the same time.
+    /// the database is only locked for the snapshot, the rest of the query runs without it.
+    pub async fn collect(
+        &self,
+        start: LouisEpoch,
+        end: LouisEpoch,
+    ) -> Result<CollectedRange, String> {
+        let snapshot = self
+            .read(move |database| database.snapshot(start, end))
+            .await?;
+        task::spawn_blocking(snapshot)
+            .await
+            .map_err(|e| format!("database task failed: {e}"))?
+    }
+    /// users by messages sent in the months `from..=to`, see `Rollups::leaderboard`.
+    pub async fn leaderboard(
+        &self,
+        from: Option<String>,
+        to: Option<String>,
+    ) -> Result<Vec<Standing>, String> {
+        self.read(move |database| {
+            Ok(database
+                .rollups()
+                .leaderboard(from.as_deref(), to.as_deref()))
+        })
+        .await
+    }
+    pub async fn rebuild_rollups(&self) -> Result<(), String> {
+        self.write(|database| database.rebuild_rollups()).await
+    }
+    pub async fn flush(&self) -> Result<(), String> {
+        self.write(|database| database.flush()).await
+    }
+    async fn read<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&ServerDatabase<S>) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let database = self.database.clone();
+        task::spawn_blocking(move || {
+            f(&*database
+                .read()
+                .map_err(|_| "database lock poisoned".to_string())?)
+        })
+        .await
+        .map_err(|e| format!("database task failed: {e}"))?
+    }
+    async fn write<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut ServerDatabase<S>) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let database = self.database.clone();
+        task::spawn_blocking(move || {
+            f(&mut *database
+                .write()
+                .map_err(|_| "database lock poisoned".to_string())?)
+        })
+        .await
+        .map_err(|e| format!("database task failed: {e}"))?
+    }
+}
+
+type Opener<S> = fn(&Path) -> Result<ServerDatabase<S>, String>;
+
+/// the handles of every server under one database directory, opened on first use.
+pub struct DatabaseRegistry<S: Storage = ServerFiles> {
+    directory: PathBuf,
+    open: Opener<S>,
+    servers: Arc<Mutex<HashMap<String, ServerHandle<S>>>>,
+}
+impl<S: Storage> Clone for DatabaseRegistry<S> {
+    fn clone(&self) -> Self {
+        Self {
+            directory: self.directory.clone(),
+            open: self.open,
+            servers: self.servers.clone(),
+        }
+    }
+}
+impl DatabaseRegistry {
+    /// servers stored as json year files, see `ServerDatabase::new`.
+    pub fn new(directory: &Path) -> Self {
+        Self::with_opener(directory, ServerDatabase::new)
+    }
+}
+impl<S: Storage + Send + Sync + 'static> DatabaseRegistry<S> {
+    /// `open` is given the directory of a server and opens its database.
+    pub fn with_opener(directory: &Path, open: Opener<S>) -> Self {
+        Self {
+            directory: directory.to_path_buf(),
+            open,
+            servers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    /// the handle of `server`, opening its database if this is the first time it is asked for.
+    pub async fn server(&self, server: &str) -> Result<ServerHandle<S>, String> {
+        // held across the open so a server is never opened twice.
+        let mut servers = self.servers.lock().await;
+        if let Some(handle) = servers.get(server) {
+            return Ok(handle.clone());
+        }
+        let path = self.directory.join(server);
+        let open = self.open;
+        let database = task::spawn_blocking(move || open(&path))
+            .await
+            .map_err(|e| format!("open task failed: {e}"))??;
+        let handle = ServerHandle::new(database);
+        servers.insert(server.to_string(), handle.clone());
+        Ok(handle)
+    }
+    pub async fn handles(&self) -> Vec<ServerHandle<S>> {
+        self.servers.lock().await.values().cloned().collect()
+    }
+    /// flush every open server, all of them are attempted even if one fails.
+    pub async fn flush_all(&self) -> Result<(), String> {
+        let mut failed = Vec::new();
+        for handle in self.handles().await {
+            if let Err(e) = handle.flush().await {
+                failed.push(e);
+            }
+        }
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(failed.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::database::{
+        journal::Journal,
+        rollups::Rollups,
+        server::UserUpdate,
+        storage::{MemoryStorage, Snapshot},
+        test_dir,
+    };
+
+    const READ_TIME: Duration = Duration::from_millis(1000);
+
+    // memory storage whose range queries take `READ_TIME` once the snapshot is taken.
+    #[derive(Default)]
+    struct SlowReads(MemoryStorage);
+    impl Storage for SlowReads {
+        fn load(&mut self, year: usize) -> Result<(), String> {
+            self.0.load(year)
+        }
+        fn update(&mut self, updates: &[UserUpdate], seq: u64) -> Result<(), String> {
+            self.0.update(updates, seq)
+        }
+        fn collect(&self, start: LouisEpoch, end: LouisEpoch) -> Result<CollectedRange, String> {
+            self.snapshot(start, end)?()
+        }
+        fn snapshot(&self, start: LouisEpoch, end: LouisEpoch) -> Result<Snapshot, String> {
+            let collected = self.0.collect(start, end)?;
+            Ok(Box::new(move || {
+                thread::sleep(READ_TIME);
+                Ok(collected)
+            }))
+        }
+        fn flush(&mut self) -> Result<(), String> {
+            self.0.flush()
+        }
+        fn applied(&self, year: usize) -> Result<u64, String> {
+            self.0.applied(year)
+        }
+        fn last_applied(&self) -> Result<u64, String> {
+            self.0.last_applied()
+        }
+    }
+
+    fn update(messages: usize) -> Vec<OwnedUserUpdate> {
+        let date = DateTime::from_timestamp(1_750_000_000, 0).unwrap();
+        vec![OwnedUserUpdate::from_update(&(
+            1,
+            "louis",
+            messages,
+            &[],
+            date,
+        ))]
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_slow_read_does_not_hold_up_writes() {
+        let path = test_dir("handle-slow-read");
+        let database = ServerDatabase::with_storage(
+            &path,
+            SlowReads::default(),
+            Some(Journal::open(&path.join("server.journal")).unwrap()),
+            Rollups::in_memory(),
+            None,
+        )
+        .unwrap();
+        let handle = ServerHandle::new(database);
+        handle.update_users(update(2)).await.unwrap();
+        let read = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.collect(0, LouisEpoch::MAX).await }
+        });
+        // long enough for the read to take its snapshot and start on the slow part.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let started = Instant::now();
+        handle.update_users(update(3)).await.unwrap();
+        assert!(started.elapsed() < READ_TIME / 2, "{:?}", started.elapsed());
+        // the read sees the database as it was when it started.
+        let collected = read.await.unwrap().unwrap();
+        let total: usize = collected.users[0].days().values().map(|d| d.total()).sum();
+        assert_eq!(total, 2);
+    }
+}
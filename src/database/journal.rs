@@ -0,0 +1,190 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::database::{
+    server::{OwnedUserUpdate, UserUpdate},
+    watermarks::Checkpoint,
+};
+
+/// one line of the journal.
+#[derive(Serialize, Deserialize)]
+pub struct JournalBatch {
+    /// numbers the batches in the order they were appended, it keeps growing across truncations.
+    /// a backend records the last batch it persisted, see `Storage::applied`.
+    pub seq: u64,
+    pub entries: Vec<OwnedUserUpdate>,
+    /// the marks the batch moves, see `ServerDatabase::update_users_checkpointed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<Checkpoint>,
+}
+
+/// append-only log of update batches which have been applied in memory but not yet flushed.
+/// every batch is one json line, synced to disk before it is applied.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    // of the last batch appended, or of the last one persisted before the journal was opened.
+    last_seq: u64,
+}
+impl Journal {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("could not create directory {parent:?}: {e}"))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(path)
+            .map_err(|e| format!("could not open journal {path:?}: {e}"))?;
+        let mut journal = Self {
+            path: path.to_path_buf(),
+            file,
+            last_seq: 0,
+        };
+        journal.last_seq = journal.replay()?.last().map_or(0, |batch| batch.seq);
+        Ok(journal)
+    }
+    /// number the next batches after `seq`, e.g. the last batch a backend persisted before the
+    /// journal was truncated. a batch numbered at or below it would never be replayed.
+    pub fn resume_after(&mut self, seq: u64) {
+        self.last_seq = self.last_seq.max(seq);
+    }
+    /// append `batch` along with the marks it moves, returns the sequence number it was given.
+    pub fn append(
+        &mut self,
+        batch: &[UserUpdate],
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<u64, String> {
+        let batch = JournalBatch {
+            seq: self.last_seq + 1,
+            entries: batch.iter().map(OwnedUserUpdate::from_update).collect(),
+            checkpoint: checkpoint.cloned(),
+        };
+        let mut line = serde_json::to_vec(&batch)
+            .map_err(|e| format!("could not serialize journal batch: {e}"))?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .map_err(|e| format!("could not append to journal {:?}: {e}", self.path))?;
+        self.file
+            .sync_data()
+            .map_err(|e| format!("could not sync journal {:?}: {e}", self.path))?;
+        self.last_seq = batch.seq;
+        Ok(batch.seq)
+    }
+    /// read back every complete batch in the journal, in the order they were appended.
+    pub fn replay(&self) -> Result<Vec<JournalBatch>, String> {
+        let file = File::open(&self.path)
+            .map_err(|e| format!("could not open journal {:?}: {e}", self.path))?;
+        let lines = BufReader::new(file)
+            .lines()
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| format!("could not read journal {:?}: {e}", self.path))?;
+        let mut batches = Vec::with_capacity(lines.len());
+        for (i, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(batch) => batches.push(batch),
+                // a crash during an append can only tear the final line, that batch was never applied.
+                Err(_) if i + 1 == lines.len() => {
+                    eprintln!("discarding torn final batch in journal {:?}", self.path)
+                }
+                Err(e) => Err(format!(
+                    "corrupt batch on line {} of journal {:?}: {e}",
+                    i + 1,
+                    self.path
+                ))?,
+            }
+        }
+        Ok(batches)
+    }
+    /// whether the journal at `path` holds batches, without opening it for writing.
+    pub fn has_batches(path: &Path) -> Result<bool, String> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(metadata.len() > 0),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(format!("could not stat journal {path:?}: {e}")),
+        }
+    }
+    pub fn is_empty(&self) -> Result<bool, String> {
+        self.file
+            .metadata()
+            .map(|m| m.len() == 0)
+            .map_err(|e| format!("could not stat journal {:?}: {e}", self.path))
+    }
+    /// drop every batch, only call once everything they contain has been flushed.
+    pub fn truncate(&mut self) -> Result<(), String> {
+        self.file
+            .set_len(0)
+            .and_then(|_| self.file.sync_all())
+            .map_err(|e| format!("could not truncate journal {:?}: {e}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::database::test_dir;
+
+    fn batch(messages: usize) -> Vec<UserUpdate<'static>> {
+        let date = DateTime::from_timestamp(1_750_000_000, 0).unwrap();
+        vec![(1, "louis", messages, &[], date)]
+    }
+
+    #[test]
+    fn numbers_batches_in_order() {
+        let path = test_dir("journal-order").join("server.journal");
+        let mut journal = Journal::open(&path).unwrap();
+        assert!(journal.is_empty().unwrap());
+        assert_eq!(journal.append(&batch(1), None).unwrap(), 1);
+        assert_eq!(journal.append(&batch(2), None).unwrap(), 2);
+        let replayed = journal.replay().unwrap();
+        assert_eq!(replayed.iter().map(|b| b.seq).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(replayed[1].entries[0].messages, 2);
+    }
+
+    #[test]
+    fn keeps_numbering_after_reopening_and_truncating() {
+        let path = test_dir("journal-numbering").join("server.journal");
+        let mut journal = Journal::open(&path).unwrap();
+        journal.append(&batch(1), None).unwrap();
+        journal.append(&batch(1), None).unwrap();
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.append(&batch(1), None).unwrap(), 3);
+        journal.truncate().unwrap();
+        assert!(journal.is_empty().unwrap());
+        let mut journal = Journal::open(&path).unwrap();
+        // the backend persisted up to 3, the journal itself no longer knows.
+        journal.resume_after(3);
+        assert_eq!(journal.append(&batch(1), None).unwrap(), 4);
+    }
+
+    #[test]
+    fn discards_a_torn_final_batch() {
+        let path = test_dir("journal-torn").join("server.journal");
+        let mut journal = Journal::open(&path).unwrap();
+        journal.append(&batch(1), None).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(b"{\"seq\":2,\"entr"))
+            .unwrap();
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.replay().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_a_corrupt_batch_before_the_last() {
+        let path = test_dir("journal-corrupt").join("server.journal");
+        fs::write(&path, "not json\n{\"seq\":1,\"entries\":[]}\n").unwrap();
+        assert!(Journal::open(&path).is_err());
+    }
+}
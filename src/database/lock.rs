@@ -0,0 +1,122 @@
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Read, Write},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const LOCK_FILE: &str = ".lock";
+// readers only hold their shared lock while loading a file, a writer waits this long for them.
+const READER_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// advisory lock on a server directory, released when dropped.
+/// a writer holds it exclusively for as long as it owns the directory,
+/// readers hold it shared while they load a file.
+pub struct DirectoryLock {
+    file: File,
+}
+impl DirectoryLock {
+    /// take the directory for writing, fails if another writer, in this process or any other, holds it.
+    pub fn exclusive(directory: &Path) -> Result<Self, String> {
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("could not create directory {directory:?}: {e}"))?;
+        let path = directory.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| format!("could not open lock file {path:?}: {e}"))?;
+        let deadline = Instant::now() + READER_TIMEOUT;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => Err(format!("could not lock {directory:?}: {e}"))?,
+            }
+            // a shared lock can still be taken only while readers alone hold the directory.
+            let only_readers = file.try_lock_shared().is_ok();
+            let _ = file.unlock();
+            if !only_readers {
+                Err(format!(
+                    "{directory:?} is already being written by another process ({}), refusing to open it",
+                    holder(&mut file)
+                ))?
+            }
+            if Instant::now() >= deadline {
+                Err(format!(
+                    "timed out waiting for readers of {directory:?} to finish"
+                ))?
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+        // record who holds the lock so a refused writer can name it.
+        file.set_len(0)
+            .and_then(|_| write!(file, "{}", std::process::id()))
+            .and_then(|_| file.sync_data())
+            .map_err(|e| format!("could not write lock file {path:?}: {e}"))?;
+        Ok(Self { file })
+    }
+    /// take the directory for reading. `None` if a writer holds it or no writer ever created it,
+    /// files are always replaced atomically so reading them anyway still sees a complete snapshot.
+    pub fn shared(directory: &Path) -> Result<Option<Self>, String> {
+        let path = directory.join(LOCK_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(format!("could not open lock file {path:?}: {e}"))?,
+        };
+        match file.try_lock_shared() {
+            Ok(()) => Ok(Some(Self { file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(format!("could not lock {path:?}: {e}")),
+        }
+    }
+}
+impl Drop for DirectoryLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+fn holder(file: &mut File) -> String {
+    let mut pid = String::new();
+    match file.read_to_string(&mut pid) {
+        Ok(_) if !pid.trim().is_empty() => format!("pid {}", pid.trim()),
+        _ => "unknown pid".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_dir;
+
+    #[test]
+    fn a_second_writer_is_refused_naming_the_holder() {
+        let directory = test_dir("lock-writers");
+        let writer = DirectoryLock::exclusive(&directory).unwrap();
+        let error = DirectoryLock::exclusive(&directory).err().unwrap();
+        let holder = format!(
+            "already being written by another process (pid {})",
+            std::process::id()
+        );
+        assert!(error.contains(&holder), "{error}");
+        drop(writer);
+        assert!(DirectoryLock::exclusive(&directory).is_ok());
+    }
+
+    #[test]
+    fn readers_share_the_directory_but_not_with_a_writer() {
+        let directory = test_dir("lock-readers");
+        let writer = DirectoryLock::exclusive(&directory).unwrap();
+        assert!(DirectoryLock::shared(&directory).unwrap().is_none());
+        drop(writer);
+        let first = DirectoryLock::shared(&directory).unwrap();
+        let second = DirectoryLock::shared(&directory).unwrap();
+        assert!(first.is_some() && second.is_some());
+    }
+}
@@ -1,4 +1,66 @@
+pub mod backfill;
+pub mod backup;
+pub mod cache;
+pub mod chat_export;
+pub mod compression;
+pub mod data_package;
 pub mod day;
 pub mod epoch;
+pub mod flusher;
+pub mod fsck;
+pub mod handle;
+pub mod journal;
+pub mod lock;
+pub mod persist;
+pub mod rollups;
+pub mod scheduler;
+pub mod schema;
 pub mod server;
+pub mod sqlite;
+pub mod storage;
+pub mod stream;
 pub mod user;
+pub mod watermarks;
+
+/// a fresh directory for one test, the os clears its temp directory eventually.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let directory = std::env::temp_dir().join(format!(
+        "louisbotrs-{name}-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// a database of `path` counting in memory, journaled and sharing `marks` like a real one.
+#[cfg(test)]
+pub fn test_database(
+    path: &std::path::Path,
+    marks: &watermarks::Marks,
+) -> server::ServerDatabase<storage::MemoryStorage> {
+    server::ServerDatabase::with_storage(
+        path,
+        storage::MemoryStorage::new(),
+        Some(journal::Journal::open(&path.join("server.journal")).unwrap()),
+        rollups::Rollups::in_memory(),
+        Some(marks.clone()),
+    )
+    .unwrap()
+}
+
+/// everything `database` counted, summed over every user.
+#[cfg(test)]
+pub fn counted<S: storage::Storage>(database: &server::ServerDatabase<S>) -> rollups::Totals {
+    database.rollups().leaderboard(None, None).iter().fold(
+        rollups::Totals::default(),
+        |totals, standing| rollups::Totals {
+            messages: totals.messages + standing.totals.messages,
+            reactions: totals.reactions + standing.totals.reactions,
+        },
+    )
+}
@@ -0,0 +1,57 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// write `data` to `path` so that a crash at any point leaves either the old file or the new one,
+/// never a truncated mix. the data goes to a temporary sibling which is synced and renamed over `path`.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+    let parent = parent_directory(path);
+    fs::create_dir_all(&parent)
+        .map_err(|e| format!("could not create directory {parent:?}: {e}"))?;
+    let tmp = temporary_sibling(path)?;
+    let result = write_and_rename(&tmp, path, data).and_then(|_| sync_directory(&parent));
+    if result.is_err() {
+        // the previous file is untouched, only the temporary needs cleaning up.
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn write_and_rename(tmp: &Path, path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut file =
+        File::create(tmp).map_err(|e| format!("could not create temporary file {tmp:?}: {e}"))?;
+    file.write_all(data)
+        .map_err(|e| format!("could not write to file {tmp:?}: {e}"))?;
+    file.sync_all()
+        .map_err(|e| format!("could not sync file {tmp:?}: {e}"))?;
+    fs::rename(tmp, path).map_err(|e| format!("could not replace {path:?} with {tmp:?}: {e}"))
+}
+
+fn parent_directory(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+fn temporary_sibling(path: &Path) -> Result<PathBuf, String> {
+    let name = path
+        .file_name()
+        .ok_or(format!("{path:?} does not contain a file name"))?
+        .to_string_lossy();
+    Ok(parent_directory(path).join(format!(".{name}.tmp")))
+}
+
+// the rename is only durable once the directory entry itself has been synced.
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> Result<(), String> {
+    File::open(directory)
+        .and_then(|d| d.sync_all())
+        .map_err(|e| format!("could not sync directory {directory:?}: {e}"))
+}
+#[cfg(not(unix))]
+fn sync_directory(_directory: &Path) -> Result<(), String> {
+    Ok(())
+}
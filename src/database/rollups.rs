@@ -0,0 +1,262 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::database::{
+    epoch::{LouisEpoch, epoch_to_unix, unix_to_epoch},
+    persist::write_atomic,
+    server::UserUpdate,
+    user::User,
+};
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Totals {
+    pub messages: u64,
+    pub reactions: u64,
+}
+impl Totals {
+    fn add(&mut self, other: Totals) {
+        self.messages += other.messages;
+        self.reactions += other.reactions;
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct UserRollup {
+    name: String,
+    // keyed by "YYYY-MM".
+    months: BTreeMap<String, Totals>,
+}
+
+/// a user's place on a leaderboard.
+pub struct Standing {
+    pub id: u64,
+    pub name: String,
+    pub totals: Totals,
+}
+
+/// running totals of a server, kept up to date with every update so leaderboards
+/// never have to walk the hourly counts of every day.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Rollups {
+    /// the last journal batch counted in these totals, later batches are replayed into them.
+    #[serde(default)]
+    applied: u64,
+    users: HashMap<u64, UserRollup>,
+    days: BTreeMap<LouisEpoch, Totals>,
+    reactions: HashMap<String, u64>,
+    // where the totals are persisted, `None` keeps them in memory only.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    dirty: bool,
+    // whether the totals were ever written to `path`.
+    #[serde(skip)]
+    stored: bool,
+}
+impl Rollups {
+    /// totals which are never written anywhere.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+    /// load the totals persisted at `path`, or start empty if there are none yet.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let mut rollups = if path.exists() {
+            let file = File::open(path).map_err(|e| format!("failed to open {path:?}: {e}"))?;
+            serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| format!("could not read rollups {path:?}: {e}"))?
+        } else {
+            Self::default()
+        };
+        rollups.stored = path.exists();
+        rollups.path = Some(path.to_path_buf());
+        Ok(rollups)
+    }
+    /// start over from every user of the server with all of their days.
+    pub fn rebuild(&mut self, users: &[User]) {
+        let (path, applied, stored) = (self.path.take(), self.applied, self.stored);
+        *self = Self {
+            path,
+            applied,
+            stored,
+            dirty: true,
+            ..Self::default()
+        };
+        for user in users {
+            for (day, d) in user.days() {
+                let reactions: Vec<(&str, u64)> = d
+                    .reactions()
+                    .map(|(name, hours)| (name, hours.iter().map(|h| *h as u64).sum()))
+                    .collect();
+                self.add(user.id, &user.name, *day, d.total() as u64, &reactions);
+            }
+        }
+    }
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+    /// persisted totals which have never been written, e.g. for a server which predates them.
+    pub fn needs_rebuild(&self) -> bool {
+        self.path.is_some() && !self.stored
+    }
+    /// count every update of journal batch `seq`, 0 if they were not journaled.
+    pub fn apply(&mut self, updates: &[UserUpdate], seq: u64) {
+        updates.iter().for_each(|update| self.update(update));
+        self.applied = self.applied.max(seq);
+    }
+    fn update(&mut self, (id, name, messages, reactions, date): &UserUpdate) {
+        let reactions: Vec<(&str, u64)> = reactions
+            .iter()
+            .map(|(name, count)| (*name, *count as u64))
+            .collect();
+        self.add(
+            *id as u64,
+            name,
+            unix_to_epoch(date),
+            *messages as u64,
+            &reactions,
+        );
+    }
+    fn add(
+        &mut self,
+        id: u64,
+        name: &str,
+        day: LouisEpoch,
+        messages: u64,
+        reactions: &[(&str, u64)],
+    ) {
+        let totals = Totals {
+            messages,
+            reactions: reactions.iter().map(|(_, count)| count).sum(),
+        };
+        let user = self.users.entry(id).or_default();
+        user.name = name.to_string();
+        user.months.entry(month_of(day)).or_default().add(totals);
+        self.days.entry(day).or_default().add(totals);
+        for (reaction, count) in reactions {
+            *self.reactions.entry(reaction.to_string()).or_default() += count;
+        }
+        self.dirty = true;
+    }
+    /// write the totals if they changed since they were loaded or last flushed.
+    pub fn flush(&mut self) -> Result<(), String> {
+        let Some(path) = self.path.as_ref().filter(|_| self.dirty) else {
+            return Ok(());
+        };
+        let serialized = serde_json::to_vec(self)
+            .map_err(|e| format!("could not serialize rollups {path:?}: {e}"))?;
+        write_atomic(path, &serialized)?;
+        self.dirty = false;
+        self.stored = true;
+        Ok(())
+    }
+    /// users by messages sent in the months `from..=to`, "YYYY-MM", open ends reaching as far as there is data.
+    /// ties are broken by id so the order is stable.
+    pub fn leaderboard(&self, from: Option<&str>, to: Option<&str>) -> Vec<Standing> {
+        let mut standings: Vec<Standing> = self
+            .users
+            .iter()
+            .map(|(id, user)| {
+                let mut totals = Totals::default();
+                user.months
+                    .iter()
+                    .filter(|(month, _)| {
+                        from.is_none_or(|from| month.as_str() >= from)
+                            && to.is_none_or(|to| month.as_str() <= to)
+                    })
+                    .for_each(|(_, t)| totals.add(*t));
+                Standing {
+                    id: *id,
+                    name: user.name.clone(),
+                    totals,
+                }
+            })
+            .filter(|standing| standing.totals != Totals::default())
+            .collect();
+        standings.sort_by(|a, b| {
+            b.totals
+                .messages
+                .cmp(&a.totals.messages)
+                .then(a.id.cmp(&b.id))
+        });
+        standings
+    }
+    /// server wide totals of every day in `start..=end` which has any.
+    pub fn days(&self, start: LouisEpoch, end: LouisEpoch) -> Vec<(LouisEpoch, Totals)> {
+        self.days
+            .range(start..=end)
+            .map(|(day, totals)| (*day, *totals))
+            .collect()
+    }
+    /// every reaction by how often it was used, most used first.
+    pub fn reactions(&self) -> Vec<(&str, u64)> {
+        let mut reactions: Vec<(&str, u64)> = self
+            .reactions
+            .iter()
+            .map(|(name, count)| (name.as_str(), *count))
+            .collect();
+        reactions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        reactions
+    }
+}
+
+fn month_of(day: LouisEpoch) -> String {
+    epoch_to_unix(day).format("%Y-%m").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::database::test_dir;
+
+    #[test]
+    fn totals_follow_every_update() {
+        let date = DateTime::from_timestamp(1_750_000_000, 0).unwrap();
+        let mut rollups = Rollups::in_memory();
+        rollups.apply(&[(1, "louis", 2, &[("👍", 3)], date)], 1);
+        rollups.apply(&[(2, "bot", 5, &[], date), (1, "louis", 1, &[], date)], 2);
+        let leaderboard = rollups.leaderboard(Some("2025-06"), Some("2025-06"));
+        assert_eq!(leaderboard.iter().map(|s| s.id).collect::<Vec<_>>(), [2, 1]);
+        assert_eq!(leaderboard[1].totals.messages, 3);
+        assert_eq!(leaderboard[1].totals.reactions, 3);
+        assert!(rollups.leaderboard(Some("2025-07"), None).is_empty());
+        let day = unix_to_epoch(&date);
+        assert_eq!(rollups.days(day, day)[0].1.messages, 8);
+        assert_eq!(rollups.applied(), 2);
+    }
+
+    #[test]
+    fn persists_the_applied_batch() {
+        let path = test_dir("rollups-persist").join("rollups.json");
+        let date = DateTime::from_timestamp(1_750_000_000, 0).unwrap();
+        let mut rollups = Rollups::open(&path).unwrap();
+        assert!(rollups.needs_rebuild());
+        rollups.apply(&[(1, "louis", 2, &[], date)], 4);
+        rollups.flush().unwrap();
+        let rollups = Rollups::open(&path).unwrap();
+        assert!(!rollups.needs_rebuild());
+        assert_eq!(rollups.applied(), 4);
+        assert_eq!(rollups.leaderboard(None, None)[0].totals.messages, 2);
+    }
+
+    #[test]
+    fn rebuild_matches_the_updates() {
+        let date = DateTime::from_timestamp(1_750_000_000, 0).unwrap();
+        let mut user = User::new(1, "louis");
+        user.update_message_count(unix_to_epoch(&date), 3, 4);
+        let mut rollups = Rollups::in_memory();
+        rollups.apply(&[(9, "stale", 1, &[], date)], 1);
+        rollups.rebuild(&[user]);
+        let leaderboard = rollups.leaderboard(None, None);
+        assert_eq!(leaderboard.len(), 1);
+        assert_eq!(leaderboard[0].totals.messages, 4);
+        assert_eq!(rollups.applied(), 1);
+    }
+}
@@ -0,0 +1,532 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::database::backfill::{Backfill, Counter, HistorySource};
+
+/// how many times in a row a rate limited request is retried before the run fails.
+pub const DEFAULT_MAX_RETRIES: usize = 5;
+
+/// where a scheduler gets the time from, so it can run without waiting in real time.
+pub trait Clock {
+    /// time since some fixed point.
+    fn now(&self) -> Duration;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock {
+    started: Instant,
+}
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+        }
+    }
+}
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.started.elapsed()
+    }
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// a clock which only moves when something sleeps on it, which returns right away.
+#[derive(Default)]
+pub struct ManualClock {
+    now: Mutex<Duration>,
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn sleep(&self, duration: Duration) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+}
+
+/// at most `requests` history requests in any window of `per`, over every job of a scheduler.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestBudget {
+    pub requests: usize,
+    pub per: Duration,
+}
+impl Default for RequestBudget {
+    fn default() -> Self {
+        Self {
+            requests: 5,
+            per: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum RunState {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+/// pauses, resumes or cancels a scheduler from another thread. a scheduler stops in between
+/// two batches, every batch counted so far is checkpointed and a later run picks up from there.
+#[derive(Clone)]
+pub struct SchedulerControl {
+    state: Arc<(Mutex<RunState>, Condvar)>,
+}
+impl SchedulerControl {
+    fn new() -> Self {
+        Self {
+            state: Arc::new((Mutex::new(RunState::Running), Condvar::new())),
+        }
+    }
+    pub fn pause(&self) {
+        self.set(RunState::Paused, |state| state == RunState::Running);
+    }
+    pub fn resume(&self) {
+        self.set(RunState::Running, |state| state == RunState::Paused);
+    }
+    pub fn cancel(&self) {
+        self.set(RunState::Cancelled, |_| true);
+    }
+    pub fn is_paused(&self) -> bool {
+        *self.lock() == RunState::Paused
+    }
+    fn set(&self, state: RunState, from: impl FnOnce(RunState) -> bool) {
+        let mut current = self.lock();
+        if from(*current) {
+            *current = state;
+            self.state.1.notify_all();
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, RunState> {
+        self.state.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// block while paused, `false` once cancelled.
+    fn wait_while_paused(&self) -> bool {
+        let state = self
+            .state
+            .1
+            .wait_while(self.lock(), |state| *state == RunState::Paused)
+            .unwrap_or_else(|e| e.into_inner());
+        *state == RunState::Running
+    }
+}
+
+/// how far the backfill of a server has gotten.
+#[derive(Clone, Default, Debug)]
+pub struct Progress {
+    pub channels: usize,
+    pub channels_done: usize,
+    /// counted during this run, along with the duplicates which were skipped.
+    pub messages: usize,
+    pub duplicates: usize,
+    pub requests: usize,
+    /// share of the history of every channel read so far, from 0 to 1, resumed runs included.
+    pub done: f64,
+    /// estimated from the pace of this run, `None` until it has made any.
+    pub eta: Option<Duration>,
+}
+
+/// the progress of every server of a scheduler by id, readable while it runs.
+#[derive(Clone, Default)]
+pub struct ProgressBoard {
+    servers: Arc<Mutex<BTreeMap<usize, Progress>>>,
+}
+impl ProgressBoard {
+    pub fn server(&self, server_id: usize) -> Option<Progress> {
+        self.lock().get(&server_id).cloned()
+    }
+    pub fn all(&self) -> BTreeMap<usize, Progress> {
+        self.lock().clone()
+    }
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<usize, Progress>> {
+        self.servers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RunOutcome {
+    Finished,
+    Cancelled,
+}
+
+struct Job<H, C> {
+    backfill: Backfill<H>,
+    database: C,
+    channels: Vec<usize>,
+    // index into `channels` of the one being read, `channels.len()` once all are done.
+    current: usize,
+    // `Progress::done` when the run started, the pace is measured from there.
+    started_at: f64,
+    // rate limited requests retried since the last one which went through.
+    retries: usize,
+    progress: Progress,
+}
+
+/// runs the backfills of several servers side by side, taking turns one batch at a time
+/// so every server moves along, and never sending more requests than the budget allows.
+pub struct Scheduler<H, C, K> {
+    jobs: Vec<Job<H, C>>,
+    budget: RequestBudget,
+    max_retries: usize,
+    clock: K,
+    control: SchedulerControl,
+    board: ProgressBoard,
+    // when each request within the last `budget.per` was sent.
+    sent: VecDeque<Duration>,
+    // time spent running, pauses left out.
+    active: Duration,
+}
+impl<H: HistorySource, C: Counter, K: Clock> Scheduler<H, C, K> {
+    pub fn new(budget: RequestBudget, clock: K) -> Self {
+        Self {
+            jobs: Vec::new(),
+            budget,
+            max_retries: DEFAULT_MAX_RETRIES,
+            clock,
+            control: SchedulerControl::new(),
+            board: ProgressBoard::default(),
+            sent: VecDeque::new(),
+            active: Duration::ZERO,
+        }
+    }
+    /// backfill `channels` with `backfill`, counting into `database`.
+    pub fn add(
+        &mut self,
+        backfill: Backfill<H>,
+        database: C,
+        channels: Vec<usize>,
+    ) -> Result<(), String> {
+        let mut job = Job {
+            backfill,
+            database,
+            progress: Progress {
+                channels: channels.len(),
+                ..Progress::default()
+            },
+            channels,
+            current: 0,
+            started_at: 0.0,
+            retries: 0,
+        };
+        job.progress.done = done(&job)?;
+        job.started_at = job.progress.done;
+        self.publish(&job);
+        self.jobs.push(job);
+        Ok(())
+    }
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.max_retries = max_retries;
+    }
+    pub fn control(&self) -> SchedulerControl {
+        self.control.clone()
+    }
+    pub fn progress(&self) -> ProgressBoard {
+        self.board.clone()
+    }
+    /// give back the backfills and databases of every job, e.g. after a cancelled run.
+    pub fn into_jobs(self) -> Vec<(Backfill<H>, C)> {
+        self.jobs
+            .into_iter()
+            .map(|job| (job.backfill, job.database))
+            .collect()
+    }
+    /// backfill every job until all are done or the scheduler is cancelled.
+    /// requests failing on a rate limit are retried once it is over, up to `max_retries` times in
+    /// a row. any other error stops the run.
+    pub fn run(&mut self) -> Result<RunOutcome, String> {
+        let mut turn = 0;
+        loop {
+            if !self.control.wait_while_paused() {
+                return Ok(RunOutcome::Cancelled);
+            }
+            let Some(i) = (0..self.jobs.len())
+                .map(|offset| (turn + offset) % self.jobs.len())
+                .find(|i| self.jobs[*i].current < self.jobs[*i].channels.len())
+            else {
+                return Ok(RunOutcome::Finished);
+            };
+            turn = i + 1;
+            let started = self.clock.now();
+            self.wait_for_budget();
+            let job = &mut self.jobs[i];
+            let channel = job.channels[job.current];
+            job.progress.requests += 1;
+            match job.backfill.step(&mut job.database, channel) {
+                Ok(Some(outcome)) => {
+                    job.retries = 0;
+                    job.progress.messages += outcome.messages;
+                    job.progress.duplicates += outcome.duplicates;
+                }
+                Ok(None) => {
+                    job.retries = 0;
+                    job.current += 1;
+                    job.progress.channels_done += 1;
+                }
+                Err(e) => match job.backfill.source_mut().retry_after() {
+                    Some(retry_after) if job.retries < self.max_retries => {
+                        job.retries += 1;
+                        self.clock.sleep(retry_after);
+                    }
+                    Some(_) => Err(format!(
+                        "backfill of channel {channel} in server {} was still rate limited \
+                         after {} retries: {e}",
+                        job.backfill.server_id(),
+                        job.retries
+                    ))?,
+                    None => Err(format!(
+                        "backfill of channel {channel} in server {} failed: {e}",
+                        job.backfill.server_id()
+                    ))?,
+                },
+            }
+            self.active += self.clock.now().saturating_sub(started);
+            let done = done(&self.jobs[i])?;
+            let job = &mut self.jobs[i];
+            job.progress.eta = eta(job.started_at, done, self.active);
+            job.progress.done = done;
+            self.publish(&self.jobs[i]);
+        }
+    }
+    /// sleep until one more request fits in the budget, then count it.
+    fn wait_for_budget(&mut self) {
+        let now = self.clock.now();
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| now.saturating_sub(*sent) >= self.budget.per)
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.budget.requests.max(1) {
+            let oldest = self.sent.pop_front().unwrap_or(now);
+            self.clock
+                .sleep((oldest + self.budget.per).saturating_sub(now));
+        }
+        self.sent.push_back(self.clock.now());
+    }
+    fn publish(&self, job: &Job<H, C>) {
+        self.board
+            .lock()
+            .insert(job.backfill.server_id(), job.progress.clone());
+    }
+}
+
+/// the average progress of every channel of `job`.
+fn done<H: HistorySource, C>(job: &Job<H, C>) -> Result<f64, String> {
+    if job.channels.is_empty() {
+        return Ok(1.0);
+    }
+    let mut done = 0.0;
+    for (i, channel) in job.channels.iter().enumerate() {
+        done += match i < job.current {
+            true => 1.0,
+            false => job.backfill.progress_of(*channel)?,
+        };
+    }
+    Ok(done / job.channels.len() as f64)
+}
+
+/// the time the rest takes at the pace `started_at` moved to `done` in `elapsed`.
+fn eta(started_at: f64, done: f64, elapsed: Duration) -> Option<Duration> {
+    let pace = (done - started_at) / elapsed.as_secs_f64();
+    (pace > 0.0 && pace.is_finite()).then(|| Duration::from_secs_f64((1.0 - done) / pace))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        backfill::{FakeHistory, HistoryMessage, last_id_at},
+        counted,
+        epoch::LOUIS_EPOCH,
+        test_database, test_dir,
+        watermarks::Marks,
+    };
+
+    const SERVER: usize = 1;
+    const CHANNEL: usize = 10;
+    const DAY: f64 = 86_400.0;
+
+    /// stops the scheduler from within a request, as an admin would while it runs.
+    struct Interrupted {
+        history: FakeHistory,
+        control: SchedulerControl,
+        // after how many requests, and whether to cancel rather than pause.
+        after: usize,
+        cancel: bool,
+    }
+    impl HistorySource for Interrupted {
+        fn messages_after(
+            &mut self,
+            channel: usize,
+            after: u64,
+            limit: usize,
+        ) -> Result<Vec<HistoryMessage>, String> {
+            let messages = self.history.messages_after(channel, after, limit);
+            if self.history.requests.len() == self.after {
+                match self.cancel {
+                    true => self.control.cancel(),
+                    false => self.control.pause(),
+                }
+            }
+            messages
+        }
+    }
+
+    fn start() -> f64 {
+        LOUIS_EPOCH.timestamp() as f64
+    }
+    /// a message on each of the first days after the louis epoch, read up to the fourth.
+    fn four_days() -> FakeHistory {
+        let mut history = FakeHistory::default();
+        for (author, days) in [1.0, 2.0, 3.0, 3.5].into_iter().enumerate() {
+            let timestamp = start() + days * DAY;
+            history.push(
+                CHANNEL,
+                HistoryMessage {
+                    id: last_id_at(timestamp),
+                    author_id: author,
+                    author_name: format!("user {author}"),
+                    timestamp,
+                    reactions: Vec::new(),
+                },
+            );
+        }
+        history
+    }
+    fn backfill<H: HistorySource>(source: H, marks: &Marks) -> Backfill<H> {
+        let mut backfill = Backfill::new(source, marks.clone(), SERVER, start() + 4.0 * DAY);
+        backfill.set_batch_size(1);
+        backfill
+    }
+    // one request every 10 seconds.
+    fn budget() -> RequestBudget {
+        RequestBudget {
+            requests: 1,
+            per: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn a_cancelled_run_reports_its_eta_and_resumes_where_it_stopped() {
+        let path = test_dir("scheduler-cancel");
+        let marks = Marks::open(&path).unwrap();
+        let mut scheduler = Scheduler::new(budget(), ManualClock::default());
+        let source = Interrupted {
+            history: four_days(),
+            control: scheduler.control(),
+            after: 2,
+            cancel: true,
+        };
+        scheduler
+            .add(
+                backfill(source, &marks),
+                test_database(&path, &marks),
+                vec![CHANNEL],
+            )
+            .unwrap();
+        assert_eq!(scheduler.run().unwrap(), RunOutcome::Cancelled);
+        let progress = scheduler.progress().server(SERVER).unwrap();
+        assert_eq!((progress.messages, progress.requests), (2, 2));
+        assert_eq!(progress.done, 0.5);
+        // half of the history in the 10 seconds the budget made the second request wait.
+        let eta = progress.eta.unwrap().as_secs_f64();
+        assert!((eta - 10.0).abs() < 1e-6, "{eta}");
+        assert_eq!(scheduler.clock.now(), Duration::from_secs(10));
+
+        let (backfill, database) = scheduler.into_jobs().pop().unwrap();
+        let mut scheduler = Scheduler::new(budget(), ManualClock::default());
+        scheduler.add(backfill, database, vec![CHANNEL]).unwrap();
+        assert_eq!(scheduler.progress().server(SERVER).unwrap().done, 0.5);
+        assert_eq!(scheduler.run().unwrap(), RunOutcome::Finished);
+        let progress = scheduler.progress().server(SERVER).unwrap();
+        assert_eq!((progress.messages, progress.channels_done), (2, 1));
+        assert_eq!(progress.done, 1.0);
+        let (_, database) = scheduler.into_jobs().pop().unwrap();
+        assert_eq!(counted(&database).messages, 4);
+    }
+
+    #[test]
+    fn a_paused_run_waits_until_it_is_resumed() {
+        let path = test_dir("scheduler-pause");
+        let marks = Marks::open(&path).unwrap();
+        let mut scheduler = Scheduler::new(budget(), ManualClock::default());
+        let control = scheduler.control();
+        let board = scheduler.progress();
+        let source = Interrupted {
+            history: four_days(),
+            control: control.clone(),
+            after: 1,
+            cancel: false,
+        };
+        scheduler
+            .add(
+                backfill(source, &marks),
+                test_database(&path, &marks),
+                vec![CHANNEL],
+            )
+            .unwrap();
+        let admin = thread::spawn(move || {
+            let waited = Instant::now();
+            while !(control.is_paused() && board.server(SERVER).unwrap().messages == 1) {
+                if waited.elapsed() > Duration::from_secs(10) {
+                    control.cancel();
+                    return 0;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            thread::sleep(Duration::from_millis(20));
+            let requests = board.server(SERVER).unwrap().requests;
+            control.resume();
+            requests
+        });
+        assert_eq!(scheduler.run().unwrap(), RunOutcome::Finished);
+        // nothing was sent while paused.
+        assert_eq!(admin.join().unwrap(), 1);
+        let (_, database) = scheduler.into_jobs().pop().unwrap();
+        assert_eq!(counted(&database).messages, 4);
+    }
+
+    #[test]
+    fn gives_up_on_a_channel_which_stays_rate_limited() {
+        let path = test_dir("scheduler-retries");
+        let marks = Marks::open(&path).unwrap();
+        let mut history = four_days();
+        history.rate_limit(1, Duration::from_secs(60));
+        let mut scheduler = Scheduler::new(budget(), ManualClock::default());
+        scheduler.set_max_retries(3);
+        scheduler
+            .add(
+                backfill(history, &marks),
+                test_database(&path, &marks),
+                vec![CHANNEL],
+            )
+            .unwrap();
+        assert!(scheduler.run().is_err());
+        assert_eq!(scheduler.clock.now(), Duration::from_secs(3 * 60));
+        let (job, _) = scheduler.into_jobs().pop().unwrap();
+        assert_eq!(job.source().requests.len(), 4);
+
+        // retries only count while nothing goes through.
+        let path = test_dir("scheduler-retries-reset");
+        let marks = Marks::open(&path).unwrap();
+        let mut history = four_days();
+        history.rate_limit(2, Duration::from_secs(60));
+        let mut scheduler = Scheduler::new(budget(), ManualClock::default());
+        scheduler.set_max_retries(1);
+        scheduler
+            .add(
+                backfill(history, &marks),
+                test_database(&path, &marks),
+                vec![CHANNEL],
+            )
+            .unwrap();
+        assert_eq!(scheduler.run().unwrap(), RunOutcome::Finished);
+    }
+}
@@ -0,0 +1,190 @@
+use serde_json::Value;
+
+use crate::database::user::User;
+
+/// files written by louisbot4 carry no version at all.
+pub const LEGACY_VERSION: u64 = 0;
+pub const CURRENT_VERSION: u64 = 1;
+
+/// how a server file is written back to disk.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum WriteMode {
+    /// only the fields louisbot4 knows about, without a version.
+    #[default]
+    Louisbot4,
+    /// the current layout tagged with `CURRENT_VERSION`.
+    Versioned,
+}
+
+/// one step of the schema, upgrading a user read from a file of version `n` to `n + 1`.
+/// users are upgraded one at a time, so a file streamed with `stream_users` is upgraded as well.
+pub type Migration = fn(&mut User) -> Result<(), String>;
+/// `MIGRATIONS[n]` upgrades a file from version `n` to `n + 1`.
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [legacy_to_v1];
+
+// the fields louisbot4 reads, anything else is dropped when writing in `WriteMode::Louisbot4`.
+const FILE_FIELDS: [&str; 3] = ["users", "reactions", "meta"];
+const META_FIELDS: [&str; 2] = ["first_day", "last_day"];
+const USER_FIELDS: [&str; 3] = ["id", "name", "days"];
+const DAY_FIELDS: [&str; 3] = ["date", "msg_hours", "emoji_hours"];
+
+/// bring the users of a file of `version` up to `CURRENT_VERSION` by running every migration
+/// after it. a newer file may hold data this version would drop when writing it back.
+pub fn migrate<'a>(
+    version: Option<u64>,
+    users: impl IntoIterator<Item = &'a mut User>,
+) -> Result<(), String> {
+    migrate_with(&MIGRATIONS, version, users)
+}
+fn migrate_with<'a>(
+    migrations: &[Migration],
+    version: Option<u64>,
+    users: impl IntoIterator<Item = &'a mut User>,
+) -> Result<(), String> {
+    let (version, current) = (version.unwrap_or(LEGACY_VERSION), migrations.len() as u64);
+    if version > current {
+        Err(format!(
+            "schema version {version} is newer than the supported version {current}"
+        ))?
+    }
+    let steps = &migrations[version as usize..];
+    if steps.is_empty() {
+        return Ok(());
+    }
+    for user in users {
+        for (from, step) in steps.iter().enumerate() {
+            step(user).map_err(|e| {
+                format!(
+                    "migration of user {} from version {} failed: {e}",
+                    user.id,
+                    version as usize + from
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// strip a serialized server file down to the fields louisbot4 knows about, version included.
+pub fn to_louisbot4(file: Value) -> Value {
+    let mut file = retain_fields(file, &FILE_FIELDS);
+    if let Some(meta) = file.get_mut("meta") {
+        *meta = retain_fields(meta.take(), &META_FIELDS);
+    }
+    if let Some(Value::Object(users)) = file.get_mut("users") {
+        for user in users.values_mut() {
+            *user = retain_fields(user.take(), &USER_FIELDS);
+            if let Some(Value::Object(days)) = user.get_mut("days") {
+                for day in days.values_mut() {
+                    *day = retain_fields(day.take(), &DAY_FIELDS);
+                }
+            }
+        }
+    }
+    file
+}
+
+fn retain_fields(value: Value, fields: &[&str]) -> Value {
+    match value {
+        Value::Object(mut object) => {
+            object.retain(|key, _| fields.contains(&key.as_str()));
+            Value::Object(object)
+        }
+        other => other,
+    }
+}
+
+// version 1 is the louisbot4 layout itself, only the version tag is new.
+fn legacy_to_v1(_: &mut User) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::database::{server::ServerFile, stream::stream_users, test_dir};
+
+    const LEGACY: &str = r#"{
+        "users": {"1": {"id": 1, "name": "louis", "days": {}}},
+        "reactions": ["👍"],
+        "meta": {"first_day": 3, "last_day": 4}
+    }"#;
+
+    #[test]
+    fn reads_every_version_up_to_the_current_one() {
+        let directory = test_dir("schema-versions");
+        let legacy = directory.join("legacy.json");
+        fs::write(&legacy, LEGACY).unwrap();
+        let file = ServerFile::load(&legacy, false).unwrap();
+        assert_eq!(file.get_all_reactions(), ["👍"]);
+        assert_eq!((file.first_day(), file.last_day()), (3, 4));
+        let versioned = directory.join("versioned.json");
+        fs::write(&versioned, LEGACY.replacen('{', "{\"version\": 1,", 1)).unwrap();
+        assert!(ServerFile::load(&versioned, false).is_ok());
+    }
+
+    #[test]
+    fn legacy_files_are_upgraded_when_loaded_or_streamed() {
+        let directory = test_dir("schema-upgrade");
+        let legacy = directory.join("legacy.json");
+        fs::write(&legacy, LEGACY).unwrap();
+        let mut streamed = Vec::new();
+        stream_users(&legacy, 0, u64::MAX, |user| streamed.push(user.name)).unwrap();
+        assert_eq!(streamed, ["louis"]);
+        let mut file = ServerFile::load(&legacy, false).unwrap();
+        file.set_write_mode(WriteMode::Versioned);
+        file.flush().unwrap();
+        let upgraded = ServerFile::read_raw(&legacy).unwrap();
+        assert_eq!(upgraded["version"], CURRENT_VERSION);
+        assert_eq!(upgraded["users"]["1"]["name"], "louis");
+    }
+
+    #[test]
+    fn migrations_run_in_order_from_the_version_of_the_file() {
+        let steps: [Migration; 2] = [
+            |user| {
+                user.name.push_str(" v1");
+                Ok(())
+            },
+            |user| {
+                user.name.push_str(" v2");
+                Ok(())
+            },
+        ];
+        let mut users = [User::new(1, "legacy"), User::new(2, "first")];
+        migrate_with(&steps, None, &mut users[..1]).unwrap();
+        migrate_with(&steps, Some(1), &mut users[1..]).unwrap();
+        assert_eq!(users[0].name, "legacy v1 v2");
+        assert_eq!(users[1].name, "first v2");
+        let error = migrate_with(&steps, Some(3), &mut users).err().unwrap();
+        assert!(error.contains("schema version 3 is newer"), "{error}");
+        let failing: [Migration; 2] = [|_| Ok(()), |_| Err("no name".to_string())];
+        let error = migrate_with(&failing, None, &mut users).err().unwrap();
+        assert_eq!(error, "migration of user 1 from version 1 failed: no name");
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let path = test_dir("schema-newer").join("newer.json");
+        fs::write(&path, LEGACY.replacen('{', "{\"version\": 2,", 1)).unwrap();
+        let error = ServerFile::load(&path, false).err().unwrap();
+        assert!(error.contains("schema version 2 is newer"), "{error}");
+    }
+
+    #[test]
+    fn louisbot4_layout_drops_unknown_fields() {
+        let file: Value = serde_json::from_str(
+            r#"{"version": 1, "extra": 0, "users": {"1": {"id": 1, "name": "l", "x": 0, "days": {}}},
+                "reactions": [], "meta": {"first_day": 0, "last_day": 0, "y": 0}}"#,
+        )
+        .unwrap();
+        let expected: Value = serde_json::from_str(
+            r#"{"users": {"1": {"id": 1, "name": "l", "days": {}}},
+                "reactions": [], "meta": {"first_day": 0, "last_day": 0}}"#,
+        )
+        .unwrap();
+        assert_eq!(to_louisbot4(file), expected);
+    }
+}
@@ -1,19 +1,83 @@
 use std::{
+    cmp::Reverse,
     collections::{HashMap, HashSet, hash_map::Entry},
     fs::{self, File},
-    io::Write,
+    io::BufReader,
     path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Instant, SystemTime},
 };
 
-use chrono::{Datelike, Timelike};
+use chrono::{DateTime, Datelike, Timelike};
+use flate2::{Crc, CrcReader};
 use serde_derive::{Deserialize, Serialize};
 
 use crate::database::{
-    epoch::{LouisEpoch, UnixEpoch, epoch_to_unix, unix_to_epoch},
+    cache::ShardCache,
+    compression::{Codec, with_codec},
+    day::Interner,
+    epoch::{LouisEpoch, UnixEpoch, unix_to_epoch, year_bounds},
+    fsck::Problem,
+    journal::{Journal, JournalBatch},
+    lock::DirectoryLock,
+    persist::write_atomic,
+    rollups::Rollups,
+    schema::{CURRENT_VERSION, WriteMode, migrate, to_louisbot4},
     server,
-    user::User,
+    storage::{CollectedRange, MemoryStorage, ShardLoad, ShardSource, Snapshot, Storage},
+    stream::stream_users,
+    user::{MergeStrategy, User},
+    watermarks::{Checkpoint, Mark, Marks},
 };
 pub type UserUpdate<'a> = (usize, &'a str, usize, &'a [(&'a str, usize)], UnixEpoch);
+// a year read during a range query, its users are `None` when its days miss the range.
+type LoadedShard = (usize, Option<Vec<User>>, ShardLoad);
+
+/// owned form of a `UserUpdate`, for updates which outlive the data they were built from.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OwnedUserUpdate {
+    pub id: usize,
+    pub name: String,
+    pub messages: usize,
+    pub reactions: Vec<(String, usize)>,
+    /// unix seconds, only the hour matters.
+    pub timestamp: i64,
+}
+impl OwnedUserUpdate {
+    pub fn from_update((id, name, messages, reactions, date): &UserUpdate) -> Self {
+        Self {
+            id: *id,
+            name: name.to_string(),
+            messages: *messages,
+            reactions: reactions.iter().map(|(r, c)| (r.to_string(), *c)).collect(),
+            timestamp: date.timestamp(),
+        }
+    }
+    /// borrow the update back as a `UserUpdate`, `reactions` has to be built from `reaction_refs`.
+    pub fn as_update<'a>(
+        &'a self,
+        reactions: &'a [(&'a str, usize)],
+    ) -> Result<UserUpdate<'a>, String> {
+        Ok((self.id, &self.name, self.messages, reactions, self.date()?))
+    }
+    pub fn date(&self) -> Result<UnixEpoch, String> {
+        DateTime::from_timestamp(self.timestamp, 0)
+            .ok_or(format!("invalid timestamp {} in update", self.timestamp))
+    }
+    pub fn year(&self) -> Result<usize, String> {
+        self.date().map(|date| date.year() as usize)
+    }
+    pub fn reaction_refs(&self) -> Vec<(&str, usize)> {
+        self.reactions
+            .iter()
+            .map(|(r, c)| (r.as_str(), *c))
+            .collect()
+    }
+}
 
 use super::epoch::now_louis_epoch;
 
@@ -22,6 +86,9 @@ struct ServerFileInit {
     users: HashMap<u64, User>,
     reactions: Vec<String>,
     meta: Meta,
+    // absent in files written by louisbot4, see `schema`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
 }
 impl ServerFileInit {
     fn from_server_file(from: ServerFile) -> Self {
@@ -29,16 +96,23 @@ impl ServerFileInit {
             users: from.users,
             reactions: from.reactions,
             meta: from.meta,
+            version: Some(CURRENT_VERSION),
         }
     }
     fn to_server_file(self, path: PathBuf, read_only: bool) -> ServerFile {
-        ServerFile {
+        ServerFile::interned(ServerFile {
             path,
             users: self.users,
             reactions: self.reactions,
             meta: self.meta,
             read_only,
-        }
+            write_mode: WriteMode::default(),
+            dirty: false,
+            interner: Interner::default(),
+            applied: 0,
+            persisted: 0,
+            on_disk: None,
+        })
     }
 }
 #[derive(Serialize, Deserialize, Clone)]
@@ -64,8 +138,119 @@ pub struct ServerFile {
     reactions: Vec<String>,
     meta: Meta,
     read_only: bool,
+    write_mode: WriteMode,
+    // changed since it was loaded or last flushed.
+    dirty: bool,
+    // the reaction names shared by every day in the file.
+    interner: Interner,
+    // the last journal batch applied to the file, and the last one on disk, see `Applied`.
+    applied: u64,
+    persisted: u64,
+    // when and how large the file was as it was read, set for read only files which are cached
+    // while a writer may replace them.
+    on_disk: Option<FileStamp>,
+}
+type FileStamp = (SystemTime, u64);
+fn stamp_of(path: &Path) -> Option<FileStamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// the journal batches a year file holds, kept next to it in `<server>_<year>.applied` since the
+/// louisbot4 layout has no room for them. it is written right before the file it describes and
+/// names the exact json that file is replaced with, so a crash in between is told apart by it.
+#[derive(Serialize, Deserialize)]
+struct Applied {
+    /// batches up to `seq` are in the file if its json has this crc and length,
+    seq: u64,
+    crc: u32,
+    len: u32,
+    /// otherwise it was never replaced and holds the batches up to `previous`.
+    previous: u64,
+}
+impl Applied {
+    fn path_of(file: &Path) -> PathBuf {
+        let name = file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        // the same for every codec, compressing a file keeps its json as it is.
+        let stem = Codec::strip(name).map_or(name, |(stem, _)| stem);
+        file.with_file_name(format!("{stem}.applied"))
+    }
+    fn read(file: &Path) -> Result<Option<Self>, String> {
+        let path = Self::path_of(file);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(&path).map_err(|e| format!("failed to open {path:?}: {e}"))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map(Some)
+            .map_err(|e| format!("could not read {path:?}: {e}"))
+    }
+    fn write(&self, file: &Path) -> Result<(), String> {
+        let path = Self::path_of(file);
+        let serialized =
+            serde_json::to_vec(self).map_err(|e| format!("could not serialize {path:?}: {e}"))?;
+        write_atomic(&path, &serialized)
+    }
+    /// the last batch held by a file whose json has `crc`.
+    fn of(&self, crc: &Crc) -> u64 {
+        match crc.sum() == self.crc && crc.amount() == self.len {
+            true => self.seq,
+            false => self.previous,
+        }
+    }
 }
 impl ServerFile {
+    /// rebuild a server file from data held elsewhere, e.g. another backend.
+    pub fn from_parts(
+        path: &Path,
+        users: Vec<User>,
+        reactions: Vec<String>,
+        first_day: LouisEpoch,
+        last_day: LouisEpoch,
+    ) -> Self {
+        Self::interned(Self {
+            path: path.to_path_buf(),
+            users: users.into_iter().map(|u| (u.id, u)).collect(),
+            reactions,
+            meta: Meta::new(first_day, last_day),
+            read_only: false,
+            write_mode: WriteMode::default(),
+            dirty: false,
+            interner: Interner::default(),
+            applied: 0,
+            persisted: 0,
+            on_disk: None,
+        })
+    }
+    // every day deserializes its own copy of each reaction name, keep one per file instead.
+    fn interned(mut self) -> Self {
+        for user in self.users.values_mut() {
+            user.intern_reactions(&mut self.interner);
+        }
+        self
+    }
+    /// an empty file for `year`, its meta holds today or the closest day of that year until the first write.
+    pub fn new_year(path: &Path, year: usize) -> Self {
+        // updates from before the epoch are counted on day 0.
+        let (first, last) = year_bounds(year).unwrap_or((0, 0));
+        let day = now_louis_epoch().clamp(first, last);
+        Self {
+            path: path.to_path_buf(),
+            users: HashMap::new(),
+            reactions: Vec::new(),
+            meta: Meta::new(day, day),
+            read_only: false,
+            write_mode: WriteMode::default(),
+            dirty: false,
+            interner: Interner::default(),
+            applied: 0,
+            persisted: 0,
+            on_disk: None,
+        }
+    }
     pub fn new(path: &str) -> Self {
         Self {
             path: PathBuf::from(path),
@@ -73,34 +258,114 @@ impl ServerFile {
             reactions: Vec::new(),
             meta: Meta::new_now(),
             read_only: false,
+            write_mode: WriteMode::default(),
+            dirty: false,
+            interner: Interner::default(),
+            applied: 0,
+            persisted: 0,
+            on_disk: None,
         }
     }
-    /// load the server file from specified path
+    /// load the server file from specified path, migrating it to the current schema.
+    /// compressed files are decoded according to their extension, see `Codec`.
+    /// a read only load does not wait for writers of the directory, see `DirectoryLock::shared`.
     pub fn load(path: &Path, read_only: bool) -> Result<Self, String> {
+        let _lock = match path.parent() {
+            Some(directory) if read_only => DirectoryLock::shared(directory)?,
+            _ => None,
+        };
+        let on_disk = read_only.then(|| stamp_of(path)).flatten();
         // let path = PathBuf::from(path);
         // let raw_json = String::new();
-        let mut file =
-            File::open(&path).map_err(|e| format!("failed to load Server \"{path:?}\": {e}"))?;
         // file.read_to_string(&mut raw_json);
-        serde_json::from_reader(file)
-            .map(|a: ServerFileInit| a.to_server_file(path.to_path_buf(), read_only))
+        let file =
+            File::open(path).map_err(|e| format!("failed to load Server \"{path:?}\": {e}"))?;
+        let mut reader = CrcReader::new(Codec::of(path).reader(file)?);
+        let init: ServerFileInit = serde_json::from_reader(BufReader::new(&mut reader))
+            .map_err(|e| format!("could not parse server file \"{path:?}\": {e}"))?;
+        let mut server = Self::from_init(path, init, read_only)?;
+        server.persisted = Applied::read(path)?.map_or(0, |applied| applied.of(reader.crc()));
+        server.applied = server.persisted;
+        server.on_disk = on_disk;
+        Ok(server)
+    }
+    /// `load` for a file which has already been read with `read_raw`, without the journal batches
+    /// it holds, see `Applied`. only for looking at, a flush would replay them all again.
+    pub fn from_raw(path: &Path, raw: serde_json::Value, read_only: bool) -> Result<Self, String> {
+        serde_json::from_value(raw)
             .map_err(|e| format!("could not parse server file \"{path:?}\": {e}"))
+            .and_then(|init| Self::from_init(path, init, read_only))
+    }
+    fn from_init(path: &Path, mut init: ServerFileInit, read_only: bool) -> Result<Self, String> {
+        migrate(init.version, init.users.values_mut())
+            .map_err(|e| format!("could not migrate server file \"{path:?}\": {e}"))?;
+        Ok(init.to_server_file(path.to_path_buf(), read_only))
+    }
+    /// the server file at `path` as plain json.
+    pub fn read_raw(path: &Path) -> Result<serde_json::Value, String> {
+        let file =
+            File::open(path).map_err(|e| format!("failed to load Server \"{path:?}\": {e}"))?;
+        let reader = Codec::of(path).reader(file)?;
+        serde_json::from_reader(BufReader::new(reader))
+            .map_err(|e| format!("could not parse server file \"{path:?}\": {e}"))
+    }
+    pub fn set_write_mode(&mut self, mode: WriteMode) {
+        self.write_mode = mode;
+    }
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+    /// whether a read only file was replaced on disk since it was read.
+    pub fn is_stale(&self) -> bool {
+        self.on_disk
+            .is_some_and(|on_disk| stamp_of(&self.path) != Some(on_disk))
+    }
+    /// the last journal batch applied to the file, whether it was flushed yet or not.
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+    /// rough number of bytes the file occupies in memory once loaded.
+    pub fn estimated_size(&self) -> usize {
+        size_of::<Self>()
+            + self
+                .users
+                .values()
+                .map(|u| size_of::<u64>() + u.estimated_size())
+                .sum::<usize>()
+            + self.reactions.iter().map(|r| r.len()).sum::<usize>()
     }
     // maybe flush should consume self, then you reinit
     // or just force ServerFileInit to have explicit lifetimes
-    fn flush(&self) -> Result<(), String> {
+    /// persist the server file, replacing the previous version atomically.
+    /// on failure the file on disk is left as it was.
+    pub fn flush(&mut self) -> Result<(), String> {
         if self.read_only {
             Err("attempted to flush a read only file".to_string())
         } else {
-            let serialized = serde_json::to_string(&ServerFileInit::from_server_file(self.clone()))// clone here is bad.
-                .map_err(|e| format!("could not serialize server {:?}: {e}", self.path))?;
-            fs::create_dir_all(&self.path)
-                .map_err(|e| format!("could not create directory {:?}: {e}", self.path))?;
-            let mut file = File::open(&self.path)
-                .map_err(|e| format!("could not open file {:?}: {e}", self.path))?;
-
-            file.write(serialized.as_bytes())
-                .map_err(|e| format!("could not write to file {:?}: {e}", self.path))?;
+            let init = ServerFileInit::from_server_file(self.clone()); // clone here is bad.
+            let serialized = match self.write_mode {
+                WriteMode::Versioned => serde_json::to_vec(&init),
+                WriteMode::Louisbot4 => {
+                    serde_json::to_value(&init).and_then(|v| serde_json::to_vec(&to_louisbot4(v)))
+                }
+            }
+            .map_err(|e| format!("could not serialize server {:?}: {e}", self.path))?;
+            // files which never held a journaled batch, e.g. exports, get no `Applied`.
+            if self.applied > 0 || self.persisted > 0 {
+                let mut crc = Crc::new();
+                crc.update(&serialized);
+                Applied {
+                    seq: self.applied,
+                    crc: crc.sum(),
+                    len: crc.amount(),
+                    previous: self.persisted,
+                }
+                .write(&self.path)?;
+            }
+            // a compressed year stays compressed.
+            write_atomic(&self.path, &Codec::of(&self.path).encode(serialized)?)?;
+            self.persisted = self.applied;
+            self.dirty = false;
             Ok(())
         }
     }
@@ -119,12 +384,74 @@ impl ServerFile {
             Entry::Vacant(vacant_entry) => vacant_entry.insert(User::new(id as u64, name)),
         }
     }
-    fn get_all_users(&self) -> Vec<&User> {
+    pub fn get_all_users(&self) -> Vec<&User> {
         self.users.values().collect()
     }
-    fn get_all_reactions(&self) -> &[String] {
+    pub fn get_all_reactions(&self) -> &[String] {
         &self.reactions
     }
+    pub fn first_day(&self) -> LouisEpoch {
+        self.meta.first_day
+    }
+    pub fn last_day(&self) -> LouisEpoch {
+        self.meta.last_day
+    }
+    /// every inconsistency `repair` knows how to fix. hour vectors of the wrong length are kept
+    /// as they were loaded, `fsck` looks for them in the raw file.
+    pub fn check(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        for (key, user) in &self.users {
+            if *key != user.id {
+                problems.push(Problem::MismatchedUserKey {
+                    key: *key,
+                    id: user.id,
+                });
+            }
+        }
+        if let Some((first, last)) = self.stored_day_range()
+            && (first < self.meta.first_day || last > self.meta.last_day)
+        {
+            problems.push(Problem::MetaMismatch {
+                recorded: (self.meta.first_day, self.meta.last_day),
+                stored: (first, last),
+            });
+        }
+        let mut unregistered: Vec<String> = self
+            .users
+            .values()
+            .flat_map(|u| u.days().values())
+            .flat_map(|d| d.reactions().map(|(name, _)| name))
+            .filter(|name| !self.reactions.iter().any(|r| r == name))
+            .map(str::to_string)
+            .collect();
+        unregistered.sort();
+        unregistered.dedup();
+        problems.extend(unregistered.into_iter().map(Problem::UnregisteredReaction));
+        problems
+    }
+    /// fix everything `check` reports, only in memory until the next flush.
+    pub fn repair(&mut self) {
+        // the key is what every lookup goes through, so it wins over the id stored in the user.
+        for (key, user) in self.users.iter_mut() {
+            user.id = *key;
+            user.repair();
+        }
+        if let Some((first, last)) = self.stored_day_range() {
+            self.meta.first_day = self.meta.first_day.min(first);
+            self.meta.last_day = self.meta.last_day.max(last);
+        }
+        for problem in self.check() {
+            if let Problem::UnregisteredReaction(name) = problem {
+                self.reactions.push(name);
+            }
+        }
+        self.dirty = true;
+    }
+    /// first and last day any user has data for.
+    fn stored_day_range(&self) -> Option<(LouisEpoch, LouisEpoch)> {
+        let days = self.users.values().flat_map(|u| u.days().keys());
+        Some((*days.clone().min()?, *days.max()?))
+    }
     fn update_last_day(&mut self, day: LouisEpoch) {
         // self.meta.get_mut("last_day").map(|d|)
         self.meta.last_day = day;
@@ -140,10 +467,67 @@ impl ServerFile {
         a.push(Self::file_name(server_name, year));
         a
     }
+    /// path of the year file of `server_name` inside the database `directory`.
+    pub fn file_path_in(directory: &Path, server_name: &str, year: usize) -> PathBuf {
+        directory.join(Self::file_path(server_name, &year.to_string()))
+    }
+    /// the year file as it is stored on disk, whichever codec it uses.
+    pub fn find_year_file(directory: &Path, server_name: &str, year: usize) -> Option<PathBuf> {
+        let plain = Self::file_path_in(directory, server_name, year);
+        Codec::ALL
+            .iter()
+            .map(|codec| with_codec(&plain, *codec))
+            .find(|path| path.exists())
+    }
+    /// every `<server>_<year>.json`, compressed or not, in the server's directory, sorted by year.
+    /// a year stored more than once is only listed under its preferred codec.
+    pub fn year_files(
+        directory: &Path,
+        server_name: &str,
+    ) -> Result<Vec<(usize, PathBuf)>, String> {
+        let server_directory = directory.join(server_name);
+        if !server_directory.exists() {
+            return Ok(Vec::new());
+        }
+        let prefix = format!("{server_name}_");
+        let mut years = fs::read_dir(&server_directory)
+            .map_err(|e| format!("could not read directory {server_directory:?}: {e}"))?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let (year, codec) = Codec::strip(name.strip_prefix(&prefix)?)?;
+                Some((year.parse::<usize>().ok()?, codec, entry.path()))
+            })
+            .collect::<Vec<(usize, Codec, PathBuf)>>();
+        years.sort_by_key(|(year, codec, _)| (*year, Codec::ALL.iter().position(|c| c == codec)));
+        years.dedup_by_key(|(year, _, _)| *year);
+        Ok(years
+            .into_iter()
+            .map(|(year, _, path)| (year, path))
+            .collect())
+    }
 
+    /// widen `meta` to cover `day`. the first write into a file without users sets the range,
+    /// so a fresh file does not keep claiming the day it was created on.
+    fn cover_day(&mut self, day: LouisEpoch, was_empty: bool) {
+        self.dirty = true;
+        if was_empty {
+            self.meta = Meta::new(day, day);
+        } else {
+            self.meta.first_day = self.meta.first_day.min(day);
+            self.meta.last_day = self.meta.last_day.max(day);
+        }
+    }
+    fn register_reaction(&mut self, reaction: &str) {
+        if !self.reactions.iter().any(|r| r == reaction) {
+            self.reactions.push(reaction.to_string());
+        }
+    }
     fn update_message_count(&mut self, user_id: usize, name: &str, date: &UnixEpoch, count: usize) {
+        let (day, was_empty) = (unix_to_epoch(date), self.users.is_empty());
         let user = self.get_or_create_user(user_id, name);
-        user.update_message_count(unix_to_epoch(date), date.hour() as usize, count);
+        user.update_message_count(day, date.hour() as usize, count);
+        self.cover_day(day, was_empty);
     }
     fn update_reaction_count(
         &mut self,
@@ -153,165 +537,709 @@ impl ServerFile {
         reaction: &str,
         count: usize,
     ) {
+        let (day, was_empty) = (unix_to_epoch(date), self.users.is_empty());
+        let interned = self.interner.intern(reaction);
         let user = self.get_or_create_user(user_id, name);
-        user.update_reaction_count(unix_to_epoch(&date), date.hour() as usize, reaction, count);
+        user.update_reaction_count(day, date.hour() as usize, &interned, count);
+        self.cover_day(day, was_empty);
+        self.register_reaction(reaction);
     }
-    fn load_serverfile(server_name: &str, year: usize) -> Result<Self, String> {
-        Self::load(&Self::file_path(server_name, &year.to_string()), false)
+    fn load_serverfile(
+        directory: &Path,
+        server_name: &str,
+        year: usize,
+        read_only: bool,
+    ) -> Result<Self, String> {
+        let path = Self::find_year_file(directory, server_name, year)
+            .unwrap_or(Self::file_path_in(directory, server_name, year));
+        Self::load(&path, read_only)
+    }
+    /// load the year file, or start an empty one if nothing has been written for that year yet.
+    /// the new file only reaches the disk on its first flush.
+    fn load_or_create_serverfile(
+        directory: &Path,
+        server_name: &str,
+        year: usize,
+    ) -> Result<Self, String> {
+        match Self::find_year_file(directory, server_name, year) {
+            Some(path) => Self::load(&path, false),
+            None => Ok(Self::new_year(
+                &Self::file_path_in(directory, server_name, year),
+                year,
+            )),
+        }
     }
 }
-struct ServerFiles {
+/// the year files of one server, loaded through a `ShardCache` which may be shared with other servers.
+pub struct ServerFiles {
     directory: PathBuf,
     server_name: String,
-    files: HashMap<usize, ServerFile>,
+    cache: Arc<Mutex<ShardCache>>,
+    write_mode: WriteMode,
+    read_only: bool,
+    // range queries read uncached years straight from disk instead of loading them.
+    streaming: bool,
+    // threads parsing uncached years at once during a range query.
+    workers: usize,
 }
-impl<'a> ServerFiles {
+impl ServerFiles {
     fn new(directory: &Path, server_name: &str) -> Self {
+        Self::with_cache(directory, server_name, ShardCache::shared())
+    }
+    pub fn with_cache(directory: &Path, server_name: &str, cache: Arc<Mutex<ShardCache>>) -> Self {
         Self {
             directory: directory.to_path_buf(),
             server_name: server_name.to_string(),
-            files: HashMap::new(),
+            cache,
+            write_mode: WriteMode::default(),
+            read_only: false,
+            streaming: false,
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
         }
     }
-    fn open_server(&'a mut self, year: usize) -> Result<&'a mut ServerFile, String> {
-        if self.files.contains_key(&year) {
-            Ok(self.files.get_mut(&year).unwrap())
-        } else {
-            self.files.insert(
-                year,
-                ServerFile::load(
-                    // ServerFile::file_name(&self.server_name.as_str(), &year.to_string()),
-                    &ServerFile::file_path(&self.server_name, &year.to_string()),
-                    false,
-                )?,
-            );
-            Ok(self.files.get_mut(&year).unwrap())
-        }
-    }
-    fn open_server_ro(&'a mut self, year: usize) -> Result<&'a ServerFile, String> {
-        // if self.files.contains_key(&year) {
-        //     Ok(self.files.get(&year).unwrap())
-        // } else {
-        //     self.files.insert(
-        //         year,
-        //         ServerFile::load(
-        //             // ServerFile::file_name(&self.server_name.as_str(), &year.to_string()),
-        //             &ServerFile::file_path(&self.server_name, &year.to_string()),
-        //             false,
-        //         )?,
-        //     );
-        self.open_server_to_database(year);
-        Ok(self.files.get(&year).unwrap())
-    }
-    fn open_server_to_database(&mut self, year: usize) -> Result<(), String> {
-        match self.files.entry(year) {
-            Entry::Occupied(_) => (),
-            Entry::Vacant(vacant_entry) => {
-                vacant_entry.insert(ServerFile::load_serverfile(&self.server_name, year)?);
-            }
-        }
+    /// read the year files without owning the directory, e.g. while the bot is writing them.
+    /// they go through the read only cache so read only shards never reach the writer's, and stay
+    /// there for later queries until the writer replaces them, see `ServerFile::is_stale`.
+    pub fn read_only(directory: &Path, server_name: &str) -> Self {
+        let mut files = Self::with_cache(directory, server_name, ShardCache::read_only());
+        files.read_only = true;
+        files
+    }
+    /// choose how every year file of this server is written from now on.
+    pub fn set_write_mode(&mut self, mode: WriteMode) -> Result<(), String> {
+        self.write_mode = mode;
+        self.lock_cache()?
+            .for_each_shard(&self.server_directory(), |f| f.set_write_mode(mode));
         Ok(())
     }
-    fn open_server_owned(&mut self, year: usize) -> Result<ServerFile, String> {
-        match self.files.entry(year) {
-            Entry::Occupied(occupied_entry) => Ok(occupied_entry.get().clone()),
-            Entry::Vacant(vacant_entry) => ServerFile::load_serverfile(&self.server_name, year),
+    /// with `streaming` set, `collect` streams the years which are not cached with `stream_users`
+    /// instead of loading them whole, for large shards which are rarely queried.
+    pub fn set_streaming(&mut self, streaming: bool) {
+        self.streaming = streaming;
+    }
+    /// how many uncached years a range query parses at once.
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers = workers.max(1);
+    }
+    fn server_directory(&self) -> PathBuf {
+        self.directory.join(&self.server_name)
+    }
+    fn lock_cache(&self) -> Result<MutexGuard<'_, ShardCache>, String> {
+        self.cache
+            .lock()
+            .map_err(|_| "shard cache lock poisoned".to_string())
+    }
+    /// run `f` on the year file, which is loaded into the cache first if needed.
+    /// with `create` a year which has not been written yet starts out empty instead of failing.
+    fn with_year<T>(
+        &self,
+        year: usize,
+        create: bool,
+        f: impl FnOnce(&mut ServerFile) -> T,
+    ) -> Result<T, String> {
+        let load = || {
+            let mut file = if create {
+                ServerFile::load_or_create_serverfile(&self.directory, &self.server_name, year)
+            } else {
+                ServerFile::load_serverfile(
+                    &self.directory,
+                    &self.server_name,
+                    year,
+                    self.read_only,
+                )
+            }?;
+            file.set_write_mode(self.write_mode);
+            Ok(file)
+        };
+        self.lock_cache()?
+            .with_shard(&self.server_directory(), year, load, f)
+    }
+    /// the users of a cached `year` with only their days in `start..=end`,
+    /// `None` if the days recorded in its meta do not overlap the range.
+    fn cached_users_in_range(
+        &self,
+        year: usize,
+        start: LouisEpoch,
+        end: LouisEpoch,
+    ) -> Result<Option<Vec<User>>, String> {
+        // only the days in range are copied out of the cached shard.
+        self.with_year(year, false, |server| {
+            overlaps(server, start, end).then(|| filtered_users(server, start, end))
+        })
+    }
+    /// what a range query needs to read uncached years once `self` is released.
+    fn disk_reader(&self) -> DiskReader {
+        DiskReader {
+            directory: self.directory.clone(),
+            server_name: self.server_name.clone(),
+            cache: self.cache.clone(),
+            write_mode: self.write_mode,
+            read_only: self.read_only,
+            streaming: self.streaming,
+            workers: self.workers,
         }
     }
+    /// every year with a shard on disk or in memory that can hold days in `start..=end`, ascending.
+    fn years_overlapping(&self, start: LouisEpoch, end: LouisEpoch) -> Result<Vec<usize>, String> {
+        let mut years: Vec<usize> = ServerFile::year_files(&self.directory, &self.server_name)?
+            .into_iter()
+            .map(|(year, _)| year)
+            .chain(self.lock_cache()?.years(&self.server_directory()))
+            .filter(|year| match year_bounds(*year) {
+                Some((first, last)) => first <= end && start <= last,
+                // only holds updates from before the epoch, which are counted on day 0.
+                None => start == 0,
+            })
+            .collect();
+        years.sort();
+        years.dedup();
+        Ok(years)
+    }
 }
 
-pub struct ServerDatabase {
-    path: PathBuf,
-    database: ServerFiles,
+/// reads the years of a range query which were not cached, apart from their `ServerFiles`
+/// so the query can finish without holding up writers, see `Storage::snapshot`.
+struct DiskReader {
+    directory: PathBuf,
+    server_name: String,
+    cache: Arc<Mutex<ShardCache>>,
+    write_mode: WriteMode,
+    read_only: bool,
+    streaming: bool,
+    workers: usize,
 }
-impl ServerDatabase {
-    pub fn new(path: &Path) -> Result<Self, String> {
-        Ok(Self {
-            path: path.to_path_buf(),
-            database: ServerFiles::new(
-                path.parent().ok_or(format!(
-                    "Invalid server path {path:?} does not contain a parent"
-                ))?,
-                path.file_name()
-                    .ok_or(format!(
-                        "Invalid server path {path:?} does not contain a basename"
-                    ))?
-                    .to_str()
-                    .ok_or(format!("could not convert OsStr to Str in {path:?}"))?,
-            ),
+impl DiskReader {
+    /// `cached_users_in_range` for a year which was not cached, so the file on disk is all of it.
+    /// the cache lock is not held while parsing.
+    fn disk_users_in_range(
+        &self,
+        year: usize,
+        start: LouisEpoch,
+        end: LouisEpoch,
+    ) -> Result<(Option<Vec<User>>, ShardSource), String> {
+        let Some(path) = ServerFile::find_year_file(&self.directory, &self.server_name, year)
+        else {
+            return Ok((None, ShardSource::Disk));
+        };
+        if self.streaming {
+            let mut users = Vec::new();
+            let summary = stream_users(&path, start, end, |user| users.push(user))?;
+            let overlapping = !(summary.last_day < start || summary.first_day > end);
+            return Ok((overlapping.then_some(users), ShardSource::Stream));
+        }
+        let stamp = stamp_of(&path);
+        let mut file = ServerFile::load(&path, self.read_only)?;
+        file.set_write_mode(self.write_mode);
+        let users = overlaps(&file, start, end).then(|| filtered_users(&file, start, end));
+        let mut cache = self
+            .cache
+            .lock()
+            .map_err(|_| "shard cache lock poisoned".to_string())?;
+        // a writer may have replaced the file while it was parsed, it is only cached if it was not.
+        // writers flush under the cache lock, so it cannot be replaced before the insert either.
+        if stamp.is_some() && stamp_of(&path) == stamp {
+            cache.insert(&self.directory.join(&self.server_name), year, file);
+        }
+        Ok((users, ShardSource::Disk))
+    }
+    /// read every year in `years` on up to `workers` threads, in no particular order.
+    fn load_in_parallel(
+        &self,
+        years: &[usize],
+        start: LouisEpoch,
+        end: LouisEpoch,
+    ) -> Result<Vec<LoadedShard>, String> {
+        let next = AtomicUsize::new(0);
+        let worker = || {
+            let mut loaded = Vec::new();
+            while let Some(year) = years.get(next.fetch_add(1, Ordering::Relaxed)) {
+                let started = Instant::now();
+                let (users, source) = self.disk_users_in_range(*year, start, end)?;
+                let load = ShardLoad {
+                    year: *year,
+                    source,
+                    elapsed: started.elapsed(),
+                };
+                loaded.push((*year, users, load));
+            }
+            Ok::<_, String>(loaded)
+        };
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.workers.clamp(1, years.len().max(1)))
+                .map(|_| scope.spawn(worker))
+                .collect();
+            let mut loaded = Vec::new();
+            for handle in workers {
+                loaded.extend(
+                    handle
+                        .join()
+                        .map_err(|_| "shard loading thread panicked".to_string())??,
+                );
+            }
+            Ok(loaded)
         })
     }
-    pub fn update_users(
-        &mut self,
-        // date: UnixEpoch,
-        data: &[UserUpdate],
-    ) -> Result<(), String> {
-        // let server = self.database.open_server(date.year() as usize)?;
-        for (id, name, messages, reactions, date) in data {
-            let server = self.database.open_server(date.year() as usize)?;
-            server.update_message_count(*id, name, date, *messages);
-            for (reaction, count) in *reactions {
-                server.update_reaction_count(*id, name, date, reaction, *count);
+}
+
+fn overlaps(server: &ServerFile, start: LouisEpoch, end: LouisEpoch) -> bool {
+    !(server.meta.last_day < start || server.meta.first_day > end)
+}
+fn filtered_users(server: &ServerFile, start: LouisEpoch, end: LouisEpoch) -> Vec<User> {
+    server
+        .users
+        .values()
+        .map(|u| u.filtered(Some(start), Some(end)))
+        .collect()
+}
+
+/// the users of every loaded shard merged into one range.
+fn merge_shards(
+    mut loaded: Vec<LoadedShard>,
+    start: LouisEpoch,
+    end: LouisEpoch,
+) -> CollectedRange {
+    // merged newest first whatever order the shards finished loading in,
+    // so the name of a user is always the one from their latest year.
+    loaded.sort_by_key(|(year, _, _)| Reverse(*year));
+    let mut collected_users: HashMap<usize, User> = HashMap::new();
+    let mut years = Vec::new();
+    let mut loads = Vec::new();
+    for (year, users, load) in loaded {
+        loads.push(load);
+        let Some(users) = users else {
+            continue;
+        };
+        years.push(year);
+        for user in users {
+            match collected_users.entry(user.id as usize) {
+                Entry::Occupied(mut occupied_entry) => {
+                    // a day can be split over two shards, its counts add up.
+                    occupied_entry.insert(occupied_entry.get().clone().combine(
+                        user,
+                        Some(start),
+                        Some(end),
+                        MergeStrategy::Sum,
+                    ));
+                }
+                Entry::Vacant(vacant_entry) => {
+                    vacant_entry.insert(user);
+                }
             }
         }
+    }
+    years.reverse();
+    loads.reverse();
+    let mut users: Vec<User> = collected_users.into_values().collect();
+    users.sort_by_key(|user| user.id);
+    CollectedRange {
+        users,
+        years,
+        loads,
+    }
+}
+
+impl Storage for ServerFiles {
+    fn load(&mut self, year: usize) -> Result<(), String> {
+        self.with_year(year, false, |_| ())
+    }
+    fn update(&mut self, updates: &[UserUpdate], seq: u64) -> Result<(), String> {
+        let mut years: Vec<usize> = updates
+            .iter()
+            .map(|(.., date)| date.year() as usize)
+            .collect();
+        years.sort();
+        years.dedup();
+        // a year takes every update of the batch under one lock, so it is never evicted
+        // holding only part of them.
+        for year in years {
+            self.with_year(year, true, |server| {
+                for (id, name, messages, reactions, date) in updates
+                    .iter()
+                    .filter(|(.., date)| date.year() as usize == year)
+                {
+                    server.update_message_count(*id, name, date, *messages);
+                    for (reaction, count) in *reactions {
+                        server.update_reaction_count(*id, name, date, reaction, *count);
+                    }
+                }
+                server.applied = server.applied.max(seq);
+            })?;
+        }
         Ok(())
     }
+    fn collect(&self, start: LouisEpoch, end: LouisEpoch) -> Result<CollectedRange, String> {
+        self.snapshot(start, end)?()
+    }
+    fn snapshot(&self, start: LouisEpoch, end: LouisEpoch) -> Result<Snapshot, String> {
+        // only years whose days can overlap the range are opened. cached years may hold changes
+        // which are not on disk yet and are copied right away, the others are parsed in parallel
+        // once the storage is released.
+        let (cached, uncached): (Vec<usize>, Vec<usize>) = {
+            let cached = {
+                let mut cache = self.lock_cache()?;
+                if self.read_only {
+                    cache.remove_where(&self.server_directory(), |_, file| file.is_stale());
+                }
+                cache.years(&self.server_directory())
+            };
+            self.years_overlapping(start, end)?
+                .into_iter()
+                .partition(|year| cached.contains(year))
+        };
+        let mut loaded = Vec::new();
+        for year in cached {
+            let started = Instant::now();
+            let users = self.cached_users_in_range(year, start, end)?;
+            let load = ShardLoad {
+                year,
+                source: ShardSource::Cache,
+                elapsed: started.elapsed(),
+            };
+            loaded.push((year, users, load));
+        }
+        let reader = self.disk_reader();
+        Ok(Box::new(move || {
+            loaded.extend(reader.load_in_parallel(&uncached, start, end)?);
+            Ok(merge_shards(loaded, start, end))
+        }))
+    }
+    fn flush(&mut self) -> Result<(), String> {
+        // unchanged years are not rewritten.
+        self.lock_cache()?.flush_server(&self.server_directory())
+    }
+    fn applied(&self, year: usize) -> Result<u64, String> {
+        // a cached year may hold batches which were not flushed yet, they are not replayed either.
+        self.with_year(year, true, |server| server.applied)
+    }
+    fn last_applied(&self) -> Result<u64, String> {
+        let mut last = 0;
+        for (_, path) in ServerFile::year_files(&self.directory, &self.server_name)? {
+            if let Some(applied) = Applied::read(&path)? {
+                last = last.max(applied.seq).max(applied.previous);
+            }
+        }
+        self.lock_cache()?
+            .for_each_shard(&self.server_directory(), |f| last = last.max(f.applied));
+        Ok(last)
+    }
+}
+
+// a closed server's shards would stay cached for as long as the process runs, and a server
+// opened again later would be handed what is still cached instead of what is on disk.
+impl Drop for ServerFiles {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        let removed = self
+            .lock_cache()
+            .and_then(|mut cache| cache.remove_server(&self.server_directory()));
+        if let Err(e) = removed {
+            eprintln!("could not close {:?}: {e}", self.server_directory());
+        }
+    }
+}
+
+pub struct ServerDatabase<S: Storage = ServerFiles> {
+    path: PathBuf,
+    database: S,
+    journal: Option<Journal>,
+    rollups: Rollups,
+    // where the checkpoints of journaled batches go, persisted before the journal is dropped.
+    marks: Option<Marks>,
+    // whether a checkpoint was applied since the last flush.
+    checkpointed: bool,
+    // held for as long as the database is open so no other process writes the same server.
+    lock: Option<DirectoryLock>,
+}
+impl ServerDatabase {
+    /// open the json server database, replaying any batches left in the journal by a crash.
+    /// fails if another process already has the server open for writing.
+    pub fn new(path: &Path) -> Result<Self, String> {
+        let server_name = path
+            .file_name()
+            .ok_or(format!(
+                "Invalid server path {path:?} does not contain a basename"
+            ))?
+            .to_str()
+            .ok_or(format!("could not convert OsStr to Str in {path:?}"))?;
+        let lock = DirectoryLock::exclusive(path)?;
+        let database_directory = path.parent().ok_or(format!(
+            "Invalid server path {path:?} does not contain a parent"
+        ))?;
+        Self::with_storage(
+            path,
+            ServerFiles::new(database_directory, server_name),
+            Some(Journal::open(&path.join(format!("{server_name}.journal")))?),
+            Rollups::open(&path.join(format!("{server_name}_rollups.json")))?,
+            Some(Marks::shared(database_directory)?),
+        )
+        .map(|database| database.locked_by(lock))
+    }
     // might be better to move to be a standalone function which owns its own database
+    /// years read for a query are kept for the next ones, see `ServerFiles::read_only`.
     pub fn collect_data(
         // &mut self,
         database_directory: &Path,
         server: &str,
         start: LouisEpoch,
         end: LouisEpoch,
-    ) -> Result<Vec<User>, String> {
-        // open serverfs and start with current year,
-        // loop get server,
-        //  if server out of range then go to next iter
-        // loop over users, if present in collection buffer combine users
-        // else copy user, filter any days outside min..max
-        // if first day outside range, break. else decrement year and open server
-        //
-        // collects users in specified range across years,
-        // direct reimplementation, try to make more functional later
-        let mut database = ServerFiles::new(database_directory, server);
-        let mut year = epoch_to_unix(end).year() as usize;
-        let mut collected_users: HashMap<usize, User> = HashMap::new();
-        loop {
-            let server = database.open_server_owned(year)?; // clone so that we can consume its data.
-            if server.meta.last_day < start || server.meta.first_day > end {
-                year -= 1;
-                continue;
-            }
-            // iterate over database
-            for (id, user) in server.users.into_iter() {
-                match collected_users.entry(id as usize) {
-                    Entry::Occupied(mut occupied_entry) => {
-                        occupied_entry.insert(occupied_entry.get().clone().combine(
-                            user,
-                            Some(start),
-                            Some(end),
-                        ));
-                    }
-                    Entry::Vacant(vacant_entry) => {
-                        vacant_entry.insert(user.filter(Some(start), Some(end)));
-                    }
+    ) -> Result<CollectedRange, String> {
+        ServerFiles::read_only(database_directory, server).collect(start, end)
+    }
+}
+impl ServerDatabase<MemoryStorage> {
+    /// a database which never touches the filesystem.
+    pub fn in_memory() -> Self {
+        Self {
+            path: PathBuf::new(),
+            database: MemoryStorage::new(),
+            journal: None,
+            rollups: Rollups::in_memory(),
+            marks: None,
+            checkpointed: false,
+            lock: None,
+        }
+    }
+}
+impl<S: Storage> ServerDatabase<S> {
+    /// run the database on top of any backend, `journal` guards batches which have not been flushed.
+    /// rollups which were never persisted are rebuilt from the backend.
+    /// `marks` are needed to count batches which move any, see `count`.
+    pub fn with_storage(
+        path: &Path,
+        database: S,
+        journal: Option<Journal>,
+        rollups: Rollups,
+        marks: Option<Marks>,
+    ) -> Result<Self, String> {
+        let mut database = Self {
+            path: path.to_path_buf(),
+            database,
+            journal,
+            rollups,
+            marks,
+            checkpointed: false,
+            lock: None,
+        };
+        // recovering persists the rollups, decided first so they are not taken for complete.
+        let rebuild = database.rollups.needs_rebuild();
+        database.recover()?;
+        if rebuild {
+            database.rebuild_rollups()?;
+        }
+        Ok(database)
+    }
+    /// keep `lock` until the database is dropped.
+    pub fn locked_by(self, lock: DirectoryLock) -> Self {
+        Self {
+            lock: Some(lock),
+            ..self
+        }
+    }
+    fn recover(&mut self) -> Result<(), String> {
+        let batches = match &mut self.journal {
+            Some(journal) => {
+                journal.resume_after(self.database.last_applied()?.max(self.rollups.applied()));
+                if journal.is_empty()? {
+                    return Ok(());
                 }
+                journal.replay()?
             }
-            if server.meta.first_day <= start {
-                break Ok(collected_users.into_values().collect());
-            } else {
-                year -= 1;
+            None => return Ok(()),
+        };
+        for batch in &batches {
+            self.replay(batch)?;
+        }
+        self.flush()
+    }
+    /// apply `batch` to the rollups and every year which did not persist it yet.
+    fn replay(&mut self, batch: &JournalBatch) -> Result<(), String> {
+        let reactions: Vec<Vec<(&str, usize)>> =
+            batch.entries.iter().map(|e| e.reaction_refs()).collect();
+        let updates = batch
+            .entries
+            .iter()
+            .zip(&reactions)
+            .map(|(entry, reactions)| entry.as_update(reactions))
+            .collect::<Result<Vec<UserUpdate>, String>>()?;
+        if batch.seq > self.rollups.applied() {
+            self.rollups.apply(&updates, batch.seq);
+        }
+        if let Some(checkpoint) = &batch.checkpoint {
+            self.apply_checkpoint(checkpoint)?;
+        }
+        // a year flushed since the batch was journaled, e.g. on eviction, already holds it.
+        let mut pending = Vec::new();
+        for update in updates {
+            if self.database.applied(update.4.year() as usize)? < batch.seq {
+                pending.push(update);
             }
         }
+        self.database.update(&pending, batch.seq)
+    }
+    /// record the batch in the journal and apply it, it is only persisted by the backend on `flush`.
+    pub fn update_users(
+        &mut self,
+        // date: UnixEpoch,
+        data: &[UserUpdate],
+    ) -> Result<(), String> {
+        self.record(data, None)
+    }
+    /// `update_users` for updates which own their data.
+    pub fn update_users_owned(&mut self, data: &[OwnedUserUpdate]) -> Result<(), String> {
+        self.update_users_checkpointed(data, None)
+    }
+    /// `update_users_owned` for a batch which moves the marks of a channel, e.g. one read by a
+    /// backfill. the checkpoint is journaled in the same record as the batch and only applied
+    /// to the marks afterwards, so they never get ahead of what was counted or fall behind it.
+    pub fn update_users_checkpointed(
+        &mut self,
+        data: &[OwnedUserUpdate],
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<(), String> {
+        let checkpoint = checkpoint.filter(|checkpoint| !checkpoint.is_empty());
+        if checkpoint.is_some() && self.marks.is_none() {
+            Err(format!("{:?} keeps no marks to checkpoint", self.path))?
+        }
+        self.record_owned(data, checkpoint)?;
+        match checkpoint {
+            Some(checkpoint) => self.apply_checkpoint(checkpoint),
+            None => Ok(()),
+        }
+    }
+    /// count `data`, the updates of `message` sent live in `channel_id` of `server_id`, unless
+    /// a backfill already counted it. returns whether it was counted. the first message counted
+    /// live in a channel is journaled along with where backfills of it stop from then on.
+    pub fn count_live(
+        &mut self,
+        server_id: usize,
+        channel_id: usize,
+        message: Mark,
+        data: &[OwnedUserUpdate],
+    ) -> Result<bool, String> {
+        let marks = self.marks.clone().ok_or(format!(
+            "{:?} keeps no marks to count live messages against",
+            self.path
+        ))?;
+        // held until the checkpoint is applied, no backfill claims the message in between.
+        let mut watermarks = marks.watermarks()?;
+        let Some(checkpoint) = watermarks.admit_live(server_id, channel_id, message) else {
+            return Ok(false);
+        };
+        let checkpoint = Some(&checkpoint).filter(|checkpoint| !checkpoint.is_empty());
+        self.record_owned(data, checkpoint)?;
+        if let Some(checkpoint) = checkpoint {
+            watermarks.apply(checkpoint);
+            self.checkpointed = true;
+        }
+        Ok(true)
+    }
+    fn record_owned(
+        &mut self,
+        data: &[OwnedUserUpdate],
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<(), String> {
+        let reactions: Vec<Vec<(&str, usize)>> = data.iter().map(|u| u.reaction_refs()).collect();
+        let updates = data
+            .iter()
+            .zip(&reactions)
+            .map(|(update, reactions)| update.as_update(reactions))
+            .collect::<Result<Vec<UserUpdate>, String>>()?;
+        self.record(&updates, checkpoint)
+    }
+    fn record(
+        &mut self,
+        data: &[UserUpdate],
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<(), String> {
+        let seq = match &mut self.journal {
+            Some(journal) => journal.append(data, checkpoint)?,
+            None => 0,
+        };
+        self.apply_updates(data, seq)
+    }
+    fn apply_updates(&mut self, data: &[UserUpdate], seq: u64) -> Result<(), String> {
+        self.rollups.apply(data, seq);
+        self.database.update(data, seq)
+    }
+    fn apply_checkpoint(&mut self, checkpoint: &Checkpoint) -> Result<(), String> {
+        let marks = self.marks.as_ref().ok_or(format!(
+            "{:?} journaled a checkpoint but keeps no marks",
+            self.path
+        ))?;
+        marks.apply(checkpoint)?;
+        self.checkpointed = true;
+        Ok(())
+    }
+    /// the marks checkpoints are applied to, `None` if the database keeps none.
+    pub fn marks(&self) -> Option<&Marks> {
+        self.marks.as_ref()
+    }
+    /// the backend, for reads that go around the journal and the rollups.
+    pub fn storage(&self) -> &S {
+        &self.database
+    }
+    /// whether this database holds the write lock on the server directory `path`.
+    pub fn holds_lock_on(&self, path: &Path) -> bool {
+        let same = match (fs::canonicalize(&self.path), fs::canonicalize(path)) {
+            (Ok(own), Ok(other)) => own == other,
+            _ => self.path == path,
+        };
+        self.lock.is_some() && same
+    }
+    /// persist every pending change, then drop the journaled batches they contain.
+    /// a crash in between replays the batches only where they were not persisted, see `Applied`.
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.database.flush()?;
+        self.rollups.flush()?;
+        if let Some(marks) = self.marks.as_ref().filter(|_| self.checkpointed) {
+            marks.flush()?;
+            self.checkpointed = false;
+        }
+        match &mut self.journal {
+            Some(journal) if !journal.is_empty()? => journal.truncate(),
+            _ => Ok(()),
+        }
+    }
+    /// collects users with their days limited to `start..=end`.
+    pub fn collect(&self, start: LouisEpoch, end: LouisEpoch) -> Result<CollectedRange, String> {
+        self.database.collect(start, end)
+    }
+    /// `collect` finishing apart from the database, see `Storage::snapshot`.
+    pub fn snapshot(&self, start: LouisEpoch, end: LouisEpoch) -> Result<Snapshot, String> {
+        self.database.snapshot(start, end)
+    }
+    pub fn rollups(&self) -> &Rollups {
+        &self.rollups
+    }
+    /// change the backend outside of the journal, e.g. to import data into it.
+    /// pending changes are flushed first and the rollups are rebuilt afterwards, even if `f` fails.
+    pub fn rewrite_storage<T>(
+        &mut self,
+        f: impl FnOnce(&mut S) -> Result<T, String>,
+    ) -> Result<T, String> {
+        self.flush()?;
+        let result = f(&mut self.database);
+        self.rebuild_rollups()?;
+        result
+    }
+    /// recompute the rollups from every day in the backend and persist them.
+    pub fn rebuild_rollups(&mut self) -> Result<(), String> {
+        let users = self.database.collect(0, LouisEpoch::MAX)?.users;
+        self.rollups.rebuild(&users);
+        self.rollups.flush()
     }
 }
+/// name of the batch cache in the database directory.
+pub const BATCH_CACHE_FILE: &str = "batch_cache.json";
 pub struct BatchCache {
     fill: usize,
     path: PathBuf,
     servers: HashMap<String, HashMap<String, f64>>,
 }
 impl BatchCache {
-    fn new(path: &Path) -> Result<Self, String> {
+    pub fn new(path: &Path) -> Result<Self, String> {
         if path.exists() {
             let file = File::open(path).map_err(|e| format!("failed to open {path:?}: {e}"))?;
             let servers = serde_json::from_reader(file)
@@ -329,24 +1257,234 @@ impl BatchCache {
             })
         }
     }
-    fn flush(&self) -> Result<(), String> {
-        serde_json::to_writer(
-            File::open(&self.path).map_err(|e| format!("failed to open {:?}: {e}", self.path))?,
-            &self.servers,
-        )
-        .map_err(|e| format!("could not serialize {:?}: {e}", self.path))
+    pub fn flush(&self) -> Result<(), String> {
+        let serialized = serde_json::to_vec(&self.servers)
+            .map_err(|e| format!("could not serialize {:?}: {e}", self.path))?;
+        write_atomic(&self.path, &serialized)
     }
-    fn log_pointer(&mut self, server_id: usize, channel_id: usize, ptr: f64) {
-        let server = match self.servers.entry(server_id.to_string()) {
-            Entry::Occupied(occupied_entry) => occupied_entry.into_mut(),
-            Entry::Vacant(vacant_entry) => vacant_entry.insert(HashMap::new()),
-        };
-        if let Some(channel) = server.get_mut(&channel_id.to_string()) {
-            *channel = ptr;
-            println!("logging pointer\n\tserver: {server_id}\n\tchannel: {channel_id}");
-        }
+    /// the unix time `channel_id` of `server_id` has been read up to, if it was ever logged.
+    pub fn pointer(&self, server_id: usize, channel_id: usize) -> Option<f64> {
+        self.servers
+            .get(&server_id.to_string())?
+            .get(&channel_id.to_string())
+            .copied()
+    }
+    pub fn log_pointer(&mut self, server_id: usize, channel_id: usize, ptr: f64) {
+        self.servers
+            .entry(server_id.to_string())
+            .or_default()
+            .insert(channel_id.to_string(), ptr);
     }
     fn clear(&mut self) {
         self.servers.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{cache::DEFAULT_CAPACITY, test_dir};
+
+    fn at(timestamp: i64) -> UnixEpoch {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+    // in 2025 and 2026, a batch touching both years.
+    const JUNE_2025: i64 = 1_750_000_000;
+    const JUNE_2026: i64 = 1_781_000_000;
+
+    fn messages<S: Storage>(database: &ServerDatabase<S>) -> usize {
+        database
+            .collect(0, LouisEpoch::MAX)
+            .unwrap()
+            .users
+            .iter()
+            .flat_map(|u| u.days().values())
+            .map(|d| d.total())
+            .sum()
+    }
+    fn journaled(path: &Path, storage: MemoryStorage) -> ServerDatabase<MemoryStorage> {
+        ServerDatabase::with_storage(
+            path,
+            storage,
+            Some(Journal::open(&path.join("server.journal")).unwrap()),
+            Rollups::in_memory(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn replays_batches_which_were_never_flushed() {
+        let path = test_dir("recover-unflushed");
+        let mut database = journaled(&path, MemoryStorage::new());
+        database
+            .update_users(&[(1, "louis", 2, &[("👍", 1)], at(JUNE_2025))])
+            .unwrap();
+        database
+            .update_users(&[(2, "bot", 3, &[], at(JUNE_2026))])
+            .unwrap();
+        // the process dies, nothing it held in memory survives.
+        let recovered = journaled(&path, MemoryStorage::new());
+        assert_eq!(messages(&recovered), 5);
+        assert_eq!(recovered.rollups().leaderboard(None, None)[0].id, 2);
+        assert_eq!(recovered.rollups().reactions(), [("👍", 1)]);
+        let journal = Journal::open(&path.join("server.journal")).unwrap();
+        assert!(journal.is_empty().unwrap());
+    }
+
+    #[test]
+    fn skips_batches_the_storage_already_holds() {
+        let path = test_dir("recover-applied");
+        let first = [(1, "louis", 2, &[][..], at(JUNE_2025))];
+        let mut database = journaled(&path, MemoryStorage::new());
+        database.update_users(&first).unwrap();
+        database
+            .update_users(&[(1, "louis", 3, &[], at(JUNE_2025))])
+            .unwrap();
+        // the storage persisted the first batch before the crash, the journal was not truncated.
+        let mut storage = MemoryStorage::new();
+        storage.update(&first, 1).unwrap();
+        let recovered = journaled(&path, storage);
+        assert_eq!(messages(&recovered), 5);
+    }
+
+    #[test]
+    fn flush_empties_the_journal_and_numbering_goes_on() {
+        let path = test_dir("flush-numbering");
+        let mut database = journaled(&path, MemoryStorage::new());
+        database
+            .update_users(&[(1, "louis", 1, &[], at(JUNE_2025))])
+            .unwrap();
+        database.flush().unwrap();
+        let journal = Journal::open(&path.join("server.journal")).unwrap();
+        assert!(journal.is_empty().unwrap());
+        database
+            .update_users(&[(1, "louis", 1, &[], at(JUNE_2025))])
+            .unwrap();
+        let journal = Journal::open(&path.join("server.journal")).unwrap();
+        assert_eq!(journal.replay().unwrap()[0].seq, 2);
+    }
+
+    // every open gets its own cache, as a new process would.
+    fn open_files(directory: &Path) -> ServerDatabase {
+        let path = directory.join("server");
+        ServerDatabase::with_storage(
+            &path,
+            ServerFiles::with_cache(
+                directory,
+                "server",
+                Arc::new(Mutex::new(ShardCache::new(DEFAULT_CAPACITY))),
+            ),
+            Some(Journal::open(&path.join("server.journal")).unwrap()),
+            Rollups::open(&path.join("server_rollups.json")).unwrap(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn a_crash_after_writing_the_years_does_not_count_twice() {
+        let directory = test_dir("recover-files");
+        let mut database = open_files(&directory);
+        database
+            .update_users(&[
+                (1, "louis", 2, &[], at(JUNE_2025)),
+                (1, "louis", 3, &[], at(JUNE_2026)),
+            ])
+            .unwrap();
+        // everything is written, but the journal is never truncated.
+        database.database.flush().unwrap();
+        database.rollups.flush().unwrap();
+        drop(database);
+        let recovered = open_files(&directory);
+        assert_eq!(messages(&recovered), 5);
+        assert_eq!(
+            recovered.rollups().leaderboard(None, None)[0]
+                .totals
+                .messages,
+            5
+        );
+    }
+
+    #[test]
+    fn closing_a_server_writes_and_drops_its_shards() {
+        let path = test_dir("close-evicts").join("server");
+        let mut database = ServerDatabase::new(&path).unwrap();
+        database
+            .update_users(&[(1, "louis", 2, &[], at(JUNE_2025))])
+            .unwrap();
+        assert_eq!(ShardCache::shared().lock().unwrap().years(&path), [2025]);
+        drop(database);
+        assert!(ShardCache::shared().lock().unwrap().years(&path).is_empty());
+        let year = ServerFile::find_year_file(path.parent().unwrap(), "server", 2025).unwrap();
+        assert_eq!(ServerFile::load(&year, true).unwrap().applied(), 1);
+    }
+
+    #[test]
+    fn read_only_queries_reuse_shards_until_the_writer_replaces_them() {
+        let path = test_dir("read-only-cache").join("server");
+        let directory = path.parent().unwrap();
+        let mut database = ServerDatabase::new(&path).unwrap();
+        database
+            .update_users(&[(1, "louis", 2, &[], at(JUNE_2025))])
+            .unwrap();
+        database.flush().unwrap();
+        let read = || ServerDatabase::collect_data(directory, "server", 0, LouisEpoch::MAX);
+        assert_eq!(read().unwrap().loads[0].source, ShardSource::Disk);
+        let cached = read().unwrap();
+        assert_eq!(cached.loads[0].source, ShardSource::Cache);
+        assert_eq!(cached.users[0].days().values().next().unwrap().total(), 2);
+        database
+            .update_users(&[(1, "louis", 3, &[], at(JUNE_2025))])
+            .unwrap();
+        database.flush().unwrap();
+        let reloaded = read().unwrap();
+        assert_eq!(reloaded.loads[0].source, ShardSource::Disk);
+        assert_eq!(reloaded.users[0].days().values().next().unwrap().total(), 5);
+    }
+
+    #[test]
+    fn a_query_starting_before_the_earliest_shard_reads_it() {
+        let directory = test_dir("query-before-shards");
+        let mut database = open_files(&directory);
+        database
+            .update_users(&[(1, "louis", 2, &[], at(JUNE_2026))])
+            .unwrap();
+        database.flush().unwrap();
+        // a file whose year no date can hold is not a reason to fail the query.
+        ServerFile::new_year(
+            &ServerFile::file_path_in(&directory, "server", 300_000),
+            300_000,
+        )
+        .flush()
+        .unwrap();
+        for start in [0, 100] {
+            let collected = database.collect(start, LouisEpoch::MAX).unwrap();
+            assert_eq!(
+                collected.users[0].days().values().next().unwrap().total(),
+                2
+            );
+        }
+    }
+
+    #[test]
+    fn a_crash_before_replacing_a_year_replays_it() {
+        let directory = test_dir("recover-torn-flush");
+        let mut database = open_files(&directory);
+        database
+            .update_users(&[(1, "louis", 2, &[], at(JUNE_2025))])
+            .unwrap();
+        database.flush().unwrap();
+        database
+            .update_users(&[(1, "louis", 3, &[], at(JUNE_2025))])
+            .unwrap();
+        let year = ServerFile::file_path_in(&directory, "server", 2025);
+        let flushed = fs::read(&year).unwrap();
+        database.database.flush().unwrap();
+        // the sidecar names the new file, but the old one is still in place.
+        fs::write(&year, flushed).unwrap();
+        drop(database);
+        let recovered = open_files(&directory);
+        assert_eq!(messages(&recovered), 5);
+    }
+}
@@ -0,0 +1,741 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use chrono::{Datelike, Timelike};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::database::{
+    day::{Day, Interner},
+    epoch::{LouisEpoch, epoch_to_unix, unix_to_epoch, year_bounds},
+    journal::Journal,
+    lock::DirectoryLock,
+    rollups::Rollups,
+    server::{ServerDatabase, ServerFile, UserUpdate},
+    storage::{CollectedRange, Storage},
+    user::User,
+    watermarks::Marks,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS days (
+    user INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    date REAL NOT NULL,
+    PRIMARY KEY (user, day)
+);
+CREATE TABLE IF NOT EXISTS messages (
+    user INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    hour INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (user, day, hour)
+);
+CREATE TABLE IF NOT EXISTS reactions (
+    user INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    hour INTEGER NOT NULL,
+    reaction TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (user, day, hour, reaction)
+);
+CREATE TABLE IF NOT EXISTS shards (
+    year INTEGER PRIMARY KEY,
+    first_day INTEGER NOT NULL,
+    last_day INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS reaction_names (
+    year INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (year, name)
+);
+CREATE TABLE IF NOT EXISTS applied (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    seq INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_day ON messages (day);
+CREATE INDEX IF NOT EXISTS reactions_day ON reactions (day);
+";
+
+/// storage keeping hourly counts as rows in an sqlite database.
+/// writes are grouped into a transaction which is committed on `flush`.
+pub struct SqliteStorage {
+    // behind a mutex so the storage can be read from several threads, see `ServerHandle`.
+    connection: Mutex<Connection>,
+    in_transaction: bool,
+    // the last journal batch applied, committed along with it.
+    applied: u64,
+}
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let connection =
+            Connection::open(path).map_err(|e| format!("could not open database {path:?}: {e}"))?;
+        Self::with_connection(connection)
+    }
+    pub fn open_in_memory() -> Result<Self, String> {
+        Self::with_connection(
+            Connection::open_in_memory()
+                .map_err(|e| format!("could not open in memory database: {e}"))?,
+        )
+    }
+    fn with_connection(connection: Connection) -> Result<Self, String> {
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|e| format!("could not create schema: {e}"))?;
+        let applied = connection
+            .query_row("SELECT seq FROM applied WHERE id = 0", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .optional()
+            .map_err(|e| format!("could not query applied batch: {e}"))?
+            .unwrap_or(0) as u64;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            in_transaction: false,
+            applied,
+        })
+    }
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>, String> {
+        self.connection
+            .lock()
+            .map_err(|_| "database connection lock poisoned".to_string())
+    }
+    fn begin(&mut self) -> Result<(), String> {
+        if !self.in_transaction {
+            self.connection()?
+                .execute_batch("BEGIN")
+                .map_err(|e| format!("could not begin transaction: {e}"))?;
+            self.in_transaction = true;
+        }
+        Ok(())
+    }
+    /// run `f` in a transaction of its own, committed if it succeeds and rolled back otherwise.
+    /// whatever was pending before is committed first.
+    fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        self.flush()?;
+        self.begin()?;
+        match f(self).and_then(|value| self.flush().map(|_| value)) {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                self.in_transaction = false;
+                self.connection()?
+                    .execute_batch("ROLLBACK")
+                    .map_err(|rollback| format!("{e}, then could not roll back: {rollback}"))?;
+                Err(e)
+            }
+        }
+    }
+    fn register_user(&self, id: usize, name: &str) -> Result<(), String> {
+        self.connection()?
+            .execute(
+                "INSERT OR IGNORE INTO users (id, name) VALUES (?1, ?2)",
+                params![id as i64, name],
+            )
+            .map(|_| ())
+            .map_err(|e| format!("could not register user {id}: {e}"))
+    }
+    fn register_day(&self, user: usize, day: LouisEpoch, date: f64) -> Result<(), String> {
+        let connection = self.connection()?;
+        let year = epoch_to_unix(day).year();
+        connection
+            .execute(
+                "INSERT OR IGNORE INTO days (user, day, date) VALUES (?1, ?2, ?3)",
+                params![user as i64, day_param(day), date],
+            )
+            .and_then(|_| {
+                connection.execute(
+                    "INSERT INTO shards (year, first_day, last_day) VALUES (?1, ?2, ?2)
+                     ON CONFLICT (year) DO UPDATE SET
+                        first_day = min(first_day, excluded.first_day),
+                        last_day = max(last_day, excluded.last_day)",
+                    params![year, day_param(day)],
+                )
+            })
+            .map(|_| ())
+            .map_err(|e| format!("could not register day {day} of user {user}: {e}"))
+    }
+    fn add_messages(
+        &self,
+        user: usize,
+        day: LouisEpoch,
+        hour: usize,
+        count: u64,
+    ) -> Result<(), String> {
+        self.connection()?
+            .execute(
+                "INSERT INTO messages (user, day, hour, count) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (user, day, hour) DO UPDATE SET count = count + excluded.count",
+                params![user as i64, day_param(day), hour as i64, count as i64],
+            )
+            .map(|_| ())
+            .map_err(|e| format!("could not add messages of user {user}: {e}"))
+    }
+    fn add_reactions(
+        &self,
+        user: usize,
+        day: LouisEpoch,
+        hour: usize,
+        reaction: &str,
+        count: u64,
+    ) -> Result<(), String> {
+        let connection = self.connection()?;
+        let year = epoch_to_unix(day).year();
+        connection
+            .execute(
+                "INSERT INTO reactions (user, day, hour, reaction, count) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (user, day, hour, reaction) DO UPDATE SET count = count + excluded.count",
+                params![user as i64, day_param(day), hour as i64, reaction, count as i64],
+            )
+            .and_then(|_| {
+                connection.execute(
+                    "INSERT OR IGNORE INTO reaction_names (year, name) VALUES (?1, ?2)",
+                    params![year, reaction],
+                )
+            })
+            .map(|_| ())
+            .map_err(|e| format!("could not add reaction {reaction} of user {user}: {e}"))
+    }
+    /// every user with at least one day in `start..=end`, their days limited to that range.
+    fn read_users(&self, start: LouisEpoch, end: LouisEpoch) -> Result<Vec<User>, String> {
+        let connection = self.connection()?;
+        let query_error = |e: rusqlite::Error| format!("could not query days {start}..={end}: {e}");
+        let mut users: HashMap<u64, User> = HashMap::new();
+        let mut days = connection
+            .prepare(
+                "SELECT days.user, users.name, days.day, days.date FROM days
+                 JOIN users ON users.id = days.user
+                 WHERE days.day BETWEEN ?1 AND ?2",
+            )
+            .map_err(query_error)?;
+        let rows = days
+            .query_map(params![day_param(start), day_param(end)], |row| {
+                Ok((
+                    row.get::<_, i64>(0)? as u64,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)? as LouisEpoch,
+                    row.get::<_, f64>(3)?,
+                ))
+            })
+            .map_err(query_error)?;
+        for row in rows {
+            let (id, name, day, date) = row.map_err(query_error)?;
+            let user = match users.entry(id) {
+                Entry::Occupied(occupied_entry) => occupied_entry.into_mut(),
+                Entry::Vacant(vacant_entry) => vacant_entry.insert(User::new(id, &name)),
+            };
+            user.insert_day(day, Day::new(date));
+        }
+        let mut messages = connection
+            .prepare("SELECT user, day, hour, count FROM messages WHERE day BETWEEN ?1 AND ?2")
+            .map_err(query_error)?;
+        let rows = messages
+            .query_map(params![day_param(start), day_param(end)], |row| {
+                Ok((
+                    row.get::<_, i64>(0)? as u64,
+                    row.get::<_, i64>(1)? as LouisEpoch,
+                    row.get::<_, i64>(2)? as usize,
+                    row.get::<_, i64>(3)? as usize,
+                ))
+            })
+            .map_err(query_error)?;
+        for row in rows {
+            let (id, day, hour, count) = row.map_err(query_error)?;
+            if let Some(user) = users.get_mut(&id) {
+                user.update_message_count(day, hour, count);
+            }
+        }
+        let mut reactions = connection
+            .prepare(
+                "SELECT user, day, hour, reaction, count FROM reactions WHERE day BETWEEN ?1 AND ?2",
+            )
+            .map_err(query_error)?;
+        let rows = reactions
+            .query_map(params![day_param(start), day_param(end)], |row| {
+                Ok((
+                    row.get::<_, i64>(0)? as u64,
+                    row.get::<_, i64>(1)? as LouisEpoch,
+                    row.get::<_, i64>(2)? as usize,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, i64>(4)? as usize,
+                ))
+            })
+            .map_err(query_error)?;
+        let mut interner = Interner::default();
+        for row in rows {
+            let (id, day, hour, reaction, count) = row.map_err(query_error)?;
+            if let Some(user) = users.get_mut(&id) {
+                user.update_reaction_count(day, hour, &interner.intern(&reaction), count);
+            }
+        }
+        Ok(users.into_values().collect())
+    }
+    /// load every `<server>_<year>.json` of `server_name` in `directory`,
+    /// replacing whatever was stored for those years. see `ServerDatabase::import_json`.
+    fn import_json(&mut self, directory: &Path, server_name: &str) -> Result<Vec<usize>, String> {
+        let years = ServerFile::year_files(directory, server_name)?;
+        self.transaction(|storage| storage.import_years(&years))?;
+        Ok(years.into_iter().map(|(year, _)| year).collect())
+    }
+    fn import_years(&self, years: &[(usize, PathBuf)]) -> Result<(), String> {
+        for (year, path) in years {
+            let file = ServerFile::load(path, true)?;
+            self.clear_year(*year)?;
+            for name in file.get_all_reactions() {
+                self.connection()?
+                    .execute(
+                        "INSERT OR IGNORE INTO reaction_names (year, name) VALUES (?1, ?2)",
+                        params![*year as i64, name],
+                    )
+                    .map_err(|e| format!("could not import reactions of {path:?}: {e}"))?;
+            }
+            for user in file.get_all_users() {
+                self.import_user(user)?;
+            }
+            // keep the recorded meta as it was, not what the imported days imply.
+            self.connection()?
+                .execute(
+                    "INSERT OR REPLACE INTO shards (year, first_day, last_day) VALUES (?1, ?2, ?3)",
+                    params![
+                        *year as i64,
+                        day_param(file.first_day()),
+                        day_param(file.last_day())
+                    ],
+                )
+                .map_err(|e| format!("could not import meta of {path:?}: {e}"))?;
+        }
+        Ok(())
+    }
+    fn import_user(&self, user: &User) -> Result<(), String> {
+        let id = user.id as usize;
+        self.register_user(id, &user.name)?;
+        for (day, d) in user.days() {
+            self.register_day(id, *day, d.date())?;
+            for (hour, count) in d.hours().iter().enumerate().filter(|(_, c)| **c > 0) {
+                self.add_messages(id, *day, hour, *count as u64)?;
+            }
+            for (reaction, hours) in d.reactions() {
+                for (hour, count) in hours.iter().enumerate().filter(|(_, c)| **c > 0) {
+                    self.add_reactions(id, *day, hour, reaction, *count as u64)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn clear_year(&self, year: usize) -> Result<(), String> {
+        let connection = self.connection()?;
+        let days = match year_bounds(year) {
+            Some((first, last)) => ["days", "messages", "reactions"]
+                .iter()
+                .try_for_each(|table| {
+                    connection
+                        .execute(
+                            &format!("DELETE FROM {table} WHERE day BETWEEN ?1 AND ?2"),
+                            params![day_param(first), day_param(last)],
+                        )
+                        .map(|_| ())
+                }),
+            // a year before the epoch has no days of its own, day 0 belongs to the epoch's year.
+            None => Ok(()),
+        };
+        days.and_then(|_| {
+            connection
+                .execute(
+                    "DELETE FROM reaction_names WHERE year = ?1",
+                    params![year as i64],
+                )
+                .map(|_| ())
+        })
+        .map_err(|e| format!("could not clear year {year}: {e}"))
+    }
+    /// write every stored year back out as `<server>/<server>_<year>.json` in `directory`,
+    /// in the exact layout louisbot4 reads. fails if the server directory is open for writing.
+    pub fn export_json(&self, directory: &Path, server_name: &str) -> Result<Vec<usize>, String> {
+        let _lock = DirectoryLock::exclusive(&directory.join(server_name))?;
+        self.export_years(directory, server_name)
+    }
+    fn export_years(&self, directory: &Path, server_name: &str) -> Result<Vec<usize>, String> {
+        let shards = self
+            .connection()?
+            .prepare("SELECT year, first_day, last_day FROM shards ORDER BY year")
+            .and_then(|mut shards| {
+                shards
+                    .query_map([], |row| {
+                        Ok((
+                            row.get::<_, i64>(0)? as usize,
+                            row.get::<_, i64>(1)? as LouisEpoch,
+                            row.get::<_, i64>(2)? as LouisEpoch,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(|e| format!("could not query shards: {e}"))?;
+        for (year, first_day, last_day) in &shards {
+            let users = match year_bounds(*year) {
+                Some((start, end)) => self.read_users(start, end)?,
+                None => Vec::new(),
+            };
+            let mut file = ServerFile::from_parts(
+                &ServerFile::file_path_in(directory, server_name, *year),
+                users,
+                self.reaction_names(*year)?,
+                *first_day,
+                *last_day,
+            );
+            file.flush()?;
+        }
+        Ok(shards.into_iter().map(|(year, _, _)| year).collect())
+    }
+    fn update_rows(&self, updates: &[UserUpdate]) -> Result<(), String> {
+        for (id, name, messages, reactions, date) in updates {
+            let (day, hour) = (unix_to_epoch(date), date.hour() as usize);
+            self.register_user(*id, name)?;
+            self.register_day(*id, day, epoch_to_unix(day).timestamp() as f64)?;
+            self.add_messages(*id, day, hour, *messages as u64)?;
+            for (reaction, count) in *reactions {
+                self.add_reactions(*id, day, hour, reaction, *count as u64)?;
+            }
+        }
+        Ok(())
+    }
+    fn reaction_names(&self, year: usize) -> Result<Vec<String>, String> {
+        self.connection()?
+            .prepare("SELECT name FROM reaction_names WHERE year = ?1 ORDER BY rowid")
+            .and_then(|mut names| {
+                names
+                    .query_map(params![year as i64], |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()
+            })
+            .map_err(|e| format!("could not query reactions of {year}: {e}"))
+    }
+    fn has_year(&self, year: usize) -> Result<bool, String> {
+        self.connection()?
+            .query_row(
+                "SELECT 1 FROM shards WHERE year = ?1",
+                params![year as i64],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|found| found.is_some())
+            .map_err(|e| format!("could not query shard {year}: {e}"))
+    }
+}
+impl Storage for SqliteStorage {
+    fn load(&mut self, year: usize) -> Result<(), String> {
+        if self.has_year(year)? {
+            Ok(())
+        } else {
+            Err(format!("no data stored for {year}"))
+        }
+    }
+    fn update(&mut self, updates: &[UserUpdate], seq: u64) -> Result<(), String> {
+        self.begin()?;
+        // a batch failing halfway is undone on its own, the batches before it stay pending.
+        self.connection()?
+            .execute_batch("SAVEPOINT batch")
+            .map_err(|e| format!("could not begin batch {seq}: {e}"))?;
+        if let Err(e) = self.update_rows(updates) {
+            self.connection()?
+                .execute_batch("ROLLBACK TO batch; RELEASE batch")
+                .map_err(|rollback| format!("{e}, then could not roll back: {rollback}"))?;
+            return Err(e);
+        }
+        self.connection()?
+            .execute_batch("RELEASE batch")
+            .map_err(|e| format!("could not end batch {seq}: {e}"))?;
+        self.applied = self.applied.max(seq);
+        Ok(())
+    }
+    fn collect(&self, start: LouisEpoch, end: LouisEpoch) -> Result<CollectedRange, String> {
+        let years = self
+            .connection()?
+            .prepare(
+                "SELECT year FROM shards WHERE first_day <= ?2 AND last_day >= ?1 ORDER BY year",
+            )
+            .and_then(|mut years| {
+                years
+                    .query_map(params![day_param(start), day_param(end)], |row| {
+                        row.get::<_, i64>(0).map(|year| year as usize)
+                    })?
+                    .collect::<Result<Vec<usize>, _>>()
+            })
+            .map_err(|e| format!("could not query shards {start}..={end}: {e}"))?;
+        Ok(CollectedRange {
+            users: self.read_users(start, end)?,
+            years,
+            loads: Vec::new(),
+        })
+    }
+    fn flush(&mut self) -> Result<(), String> {
+        if self.in_transaction {
+            self.connection()?
+                .execute(
+                    "INSERT OR REPLACE INTO applied (id, seq) VALUES (0, ?1)",
+                    params![self.applied as i64],
+                )
+                .map_err(|e| format!("could not record applied batch: {e}"))?;
+            self.connection()?
+                .execute_batch("COMMIT")
+                .map_err(|e| format!("could not commit transaction: {e}"))?;
+            self.in_transaction = false;
+        }
+        Ok(())
+    }
+    fn applied(&self, _year: usize) -> Result<u64, String> {
+        // every year is committed together.
+        Ok(self.applied)
+    }
+    fn last_applied(&self) -> Result<u64, String> {
+        Ok(self.applied)
+    }
+}
+
+/// sqlite stores days as signed integers, open ended ranges reach up to the largest one.
+fn day_param(day: LouisEpoch) -> i64 {
+    day.min(i64::MAX as LouisEpoch) as i64
+}
+
+impl ServerDatabase<SqliteStorage> {
+    /// open the sqlite database of the server at `path`, i.e. `<path>/<server>.sqlite3`.
+    pub fn new_sqlite(path: &Path) -> Result<Self, String> {
+        let server_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(format!(
+                "Invalid server path {path:?} does not contain a basename"
+            ))?;
+        let lock = DirectoryLock::exclusive(path)?;
+        let database_directory = path.parent().ok_or(format!(
+            "Invalid server path {path:?} does not contain a parent"
+        ))?;
+        Self::with_storage(
+            path,
+            SqliteStorage::open(&path.join(format!("{server_name}.sqlite3")))?,
+            Some(Journal::open(
+                &path.join(format!("{server_name}.sqlite3.journal")),
+            )?),
+            Rollups::open(&path.join(format!("{server_name}.sqlite3.rollups.json")))?,
+            Some(Marks::shared(database_directory)?),
+        )
+        .map(|database| database.locked_by(lock))
+    }
+    /// `SqliteStorage::import_json`, the rollups are rebuilt to include the imported years.
+    pub fn import_json(
+        &mut self,
+        directory: &Path,
+        server_name: &str,
+    ) -> Result<Vec<usize>, String> {
+        self.rewrite_storage(|storage| storage.import_json(directory, server_name))
+    }
+    /// `SqliteStorage::export_json` with the pending batches committed first.
+    /// exporting into this database's own server directory goes under the lock it already holds.
+    pub fn export_json(
+        &mut self,
+        directory: &Path,
+        server_name: &str,
+    ) -> Result<Vec<usize>, String> {
+        self.flush()?;
+        if self.holds_lock_on(&directory.join(server_name)) {
+            self.storage().export_years(directory, server_name)
+        } else {
+            self.storage().export_json(directory, server_name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::database::test_dir;
+
+    fn write_year(directory: &Path, year: usize, day: LouisEpoch, messages: usize) {
+        let mut user = User::new(1, "louis");
+        user.update_message_count(day, 12, messages);
+        ServerFile::from_parts(
+            &ServerFile::file_path_in(directory, "server", year),
+            vec![user],
+            Vec::new(),
+            day,
+            day,
+        )
+        .flush()
+        .unwrap();
+    }
+    fn messages(storage: &SqliteStorage) -> usize {
+        storage
+            .collect(0, LouisEpoch::MAX)
+            .unwrap()
+            .users
+            .iter()
+            .flat_map(|u| u.days().values())
+            .map(|d| d.total())
+            .sum()
+    }
+
+    #[test]
+    fn a_failed_import_is_rolled_back() {
+        let directory = test_dir("sqlite-import-rollback");
+        fs::create_dir_all(directory.join("server")).unwrap();
+        write_year(&directory, 2025, 100, 3);
+        fs::write(
+            ServerFile::file_path_in(&directory, "server", 2026),
+            "{\"users\":",
+        )
+        .unwrap();
+        let mut storage = SqliteStorage::open_in_memory().unwrap();
+        assert!(storage.import_json(&directory, "server").is_err());
+        assert_eq!(messages(&storage), 0);
+        // the connection is usable again, nothing was left open.
+        let date = epoch_to_unix(100);
+        storage.update(&[(1, "louis", 2, &[], date)], 1).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(messages(&storage), 2);
+    }
+
+    #[test]
+    fn a_failed_update_is_rolled_back_alone() {
+        let mut storage = SqliteStorage::open_in_memory().unwrap();
+        let date = epoch_to_unix(100);
+        storage.update(&[(1, "louis", 2, &[], date)], 1).unwrap();
+        // the reactions of the second batch can no longer be written, its messages already were.
+        storage
+            .connection()
+            .unwrap()
+            .execute_batch(
+                "CREATE TEMP TRIGGER refuse BEFORE INSERT ON reactions
+                 BEGIN SELECT RAISE(ABORT, 'refused'); END",
+            )
+            .unwrap();
+        let reactions = [("👍", 1)];
+        assert!(
+            storage
+                .update(&[(1, "louis", 3, &reactions, date)], 2)
+                .is_err()
+        );
+        storage.flush().unwrap();
+        storage
+            .connection()
+            .unwrap()
+            .execute_batch("DROP TRIGGER refuse")
+            .unwrap();
+        assert_eq!(messages(&storage), 2);
+        assert_eq!(storage.last_applied().unwrap(), 1);
+    }
+
+    #[test]
+    fn exported_years_are_identical_to_the_imported_ones() {
+        let directory = test_dir("sqlite-export-roundtrip");
+        fs::create_dir_all(directory.join("server")).unwrap();
+        let mut users = [User::new(1, "louis"), User::new(2, "clara")];
+        users[0].update_message_count(100, 12, 3);
+        users[0].update_reaction_count(100, 13, &"👍".into(), 2);
+        users[1].update_message_count(400, 0, 1);
+        for (year, first, last) in [(2025, 100, 100), (2026, 365, 400)] {
+            ServerFile::from_parts(
+                &ServerFile::file_path_in(&directory, "server", year),
+                users
+                    .iter()
+                    .filter(|u| u.days().keys().any(|day| (first..=last).contains(day)))
+                    .cloned()
+                    .collect(),
+                vec!["👍".to_string()],
+                first,
+                last,
+            )
+            .flush()
+            .unwrap();
+        }
+        let mut storage = SqliteStorage::open_in_memory().unwrap();
+        storage.import_json(&directory, "server").unwrap();
+        let exported = test_dir("sqlite-export-roundtrip-out");
+        assert_eq!(
+            storage.export_json(&exported, "server").unwrap(),
+            [2025, 2026]
+        );
+        for year in [2025, 2026] {
+            assert_eq!(
+                fs::read(ServerFile::file_path_in(&exported, "server", year)).unwrap(),
+                fs::read(ServerFile::file_path_in(&directory, "server", year)).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn exporting_into_its_own_directory_uses_the_held_lock() {
+        let directory = test_dir("sqlite-export-own");
+        let mut database = ServerDatabase::new_sqlite(&directory.join("server")).unwrap();
+        database
+            .update_users(&[(1, "louis", 2, &[], epoch_to_unix(100))])
+            .unwrap();
+        assert_eq!(database.export_json(&directory, "server").unwrap(), [2025]);
+        let file = ServerFile::load(&ServerFile::file_path_in(&directory, "server", 2025), true);
+        assert_eq!(file.unwrap().get_all_users().len(), 1);
+        // the storage alone does not hold the lock and is refused.
+        assert!(
+            database
+                .storage()
+                .export_json(&directory, "server")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn importing_a_year_before_the_epoch_keeps_day_0() {
+        let directory = test_dir("sqlite-import-2024");
+        fs::create_dir_all(directory.join("server")).unwrap();
+        write_year(&directory, 2025, 0, 3);
+        let mut storage = SqliteStorage::open_in_memory().unwrap();
+        storage.import_json(&directory, "server").unwrap();
+        fs::remove_file(ServerFile::file_path_in(&directory, "server", 2025)).unwrap();
+        ServerFile::new_year(&ServerFile::file_path_in(&directory, "server", 2024), 2024)
+            .flush()
+            .unwrap();
+        assert_eq!(storage.import_json(&directory, "server").unwrap(), [2024]);
+        assert_eq!(messages(&storage), 3);
+    }
+
+    #[test]
+    fn imported_years_are_counted_in_the_rollups() {
+        let directory = test_dir("sqlite-import-rollups");
+        fs::create_dir_all(directory.join("server")).unwrap();
+        write_year(&directory, 2025, 100, 3);
+        let mut database = ServerDatabase::new_sqlite(&directory.join("server")).unwrap();
+        assert_eq!(database.import_json(&directory, "server").unwrap(), [2025]);
+        let leaderboard = database.rollups().leaderboard(None, None);
+        assert_eq!(leaderboard[0].totals.messages, 3);
+    }
+
+    #[test]
+    fn missing_rollups_are_rebuilt_with_the_replayed_batches() {
+        let path = test_dir("sqlite-rebuild-rollups").join("server");
+        fs::create_dir_all(&path).unwrap();
+        let mut database = ServerDatabase::new_sqlite(&path).unwrap();
+        database
+            .update_users(&[(1, "louis", 2, &[], epoch_to_unix(100))])
+            .unwrap();
+        database.flush().unwrap();
+        database
+            .update_users(&[(1, "louis", 3, &[], epoch_to_unix(101))])
+            .unwrap();
+        // the process dies before flushing the second batch and the rollups get lost.
+        drop(database);
+        fs::remove_file(path.join("server.sqlite3.rollups.json")).unwrap();
+        let database = ServerDatabase::new_sqlite(&path).unwrap();
+        let leaderboard = database.rollups().leaderboard(None, None);
+        assert_eq!(leaderboard[0].totals.messages, 5);
+        assert_eq!(
+            messages(&SqliteStorage::open(&path.join("server.sqlite3")).unwrap()),
+            5
+        );
+    }
+}
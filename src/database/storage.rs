@@ -0,0 +1,136 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    time::Duration,
+};
+
+use chrono::{Datelike, Timelike};
+
+use crate::database::{
+    day::{Interner, Reaction},
+    epoch::{LouisEpoch, epoch_to_unix, unix_to_epoch},
+    server::UserUpdate,
+    user::User,
+};
+
+/// users collected over a range of days, along with the years which held data for it.
+pub struct CollectedRange {
+    pub users: Vec<User>,
+    pub years: Vec<usize>,
+    /// how long reading each shard took, oldest first. empty for backends without shards.
+    pub loads: Vec<ShardLoad>,
+}
+
+/// where a shard was read from during a range query.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ShardSource {
+    /// already loaded in the `ShardCache`.
+    Cache,
+    /// parsed whole from disk, then kept in the cache.
+    Disk,
+    /// only the days in range were read from disk, see `stream_users`.
+    Stream,
+}
+
+#[derive(Debug)]
+pub struct ShardLoad {
+    pub year: usize,
+    pub source: ShardSource,
+    pub elapsed: Duration,
+}
+
+/// the rest of a range query once the storage it started on is released, see `Storage::snapshot`.
+pub type Snapshot = Box<dyn FnOnce() -> Result<CollectedRange, String> + Send>;
+
+/// a backend holding the counts of a single server.
+/// `ServerDatabase` only ever talks to its data through this trait.
+pub trait Storage {
+    /// make the data of `year` available, fails if it cannot be read.
+    fn load(&mut self, year: usize) -> Result<(), String>;
+    /// apply every update to wherever its date belongs. `seq` is the journal batch they come from,
+    /// 0 if they were not journaled, it is persisted along with them, see `applied`.
+    fn update(&mut self, updates: &[UserUpdate], seq: u64) -> Result<(), String>;
+    /// collects every user with their days limited to `start..=end`.
+    fn collect(&self, start: LouisEpoch, end: LouisEpoch) -> Result<CollectedRange, String>;
+    /// `collect` split in two, only the returned query has to run after the storage is released,
+    /// so slow reads, e.g. of files on disk, do not hold up writers. by default all of it runs here.
+    fn snapshot(&self, start: LouisEpoch, end: LouisEpoch) -> Result<Snapshot, String> {
+        let collected = self.collect(start, end)?;
+        Ok(Box::new(move || Ok(collected)))
+    }
+    /// persist all pending changes.
+    fn flush(&mut self) -> Result<(), String>;
+    /// the last journal batch whose updates to `year` are persisted, 0 if there are none.
+    /// batches up to it are not replayed into the year again.
+    fn applied(&self, year: usize) -> Result<u64, String>;
+    /// the last journal batch persisted in any year, new batches are numbered after it.
+    fn last_applied(&self) -> Result<u64, String>;
+}
+
+/// storage which keeps everything in memory and never touches the filesystem.
+#[derive(Default)]
+pub struct MemoryStorage {
+    users: HashMap<u64, User>,
+    interner: Interner,
+    // the last journal batch applied, there is nothing else to persist it to.
+    applied: u64,
+}
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn get_or_create_user(&mut self, id: usize, name: &str) -> &mut User {
+        match self.users.entry(id as u64) {
+            Entry::Occupied(occupied_entry) => occupied_entry.into_mut(),
+            Entry::Vacant(vacant_entry) => vacant_entry.insert(User::new(id as u64, name)),
+        }
+    }
+}
+impl Storage for MemoryStorage {
+    fn load(&mut self, _year: usize) -> Result<(), String> {
+        Ok(())
+    }
+    fn update(&mut self, updates: &[UserUpdate], seq: u64) -> Result<(), String> {
+        for (id, name, messages, reactions, date) in updates {
+            let reactions: Vec<(Reaction, usize)> = reactions
+                .iter()
+                .map(|(reaction, count)| (self.interner.intern(reaction), *count))
+                .collect();
+            let user = self.get_or_create_user(*id, name);
+            let (day, hour) = (unix_to_epoch(date), date.hour() as usize);
+            user.update_message_count(day, hour, *messages);
+            for (reaction, count) in &reactions {
+                user.update_reaction_count(day, hour, reaction, *count);
+            }
+        }
+        self.applied = self.applied.max(seq);
+        Ok(())
+    }
+    fn collect(&self, start: LouisEpoch, end: LouisEpoch) -> Result<CollectedRange, String> {
+        let users: Vec<User> = self
+            .users
+            .values()
+            .map(|u| u.clone().filter(Some(start), Some(end)))
+            .collect();
+        let mut years: Vec<usize> = users
+            .iter()
+            .flat_map(|u| u.days().keys())
+            .map(|day| epoch_to_unix(*day).year() as usize)
+            .collect();
+        years.sort();
+        years.dedup();
+        Ok(CollectedRange {
+            users,
+            years,
+            loads: Vec::new(),
+        })
+    }
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+    fn applied(&self, _year: usize) -> Result<u64, String> {
+        Ok(self.applied)
+    }
+    fn last_applied(&self) -> Result<u64, String> {
+        Ok(self.applied)
+    }
+}
@@ -0,0 +1,279 @@
+use std::{collections::HashMap, fmt, fs::File, io::BufReader, path::Path};
+
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_derive::Deserialize;
+
+use crate::database::{
+    compression::Codec, day::Day, epoch::LouisEpoch, lock::DirectoryLock, schema::migrate,
+    user::User,
+};
+
+/// everything in a streamed year file besides its users.
+pub struct ShardSummary {
+    pub reactions: Vec<String>,
+    pub first_day: LouisEpoch,
+    pub last_day: LouisEpoch,
+}
+
+#[derive(Deserialize)]
+struct Meta {
+    first_day: LouisEpoch,
+    last_day: LouisEpoch,
+}
+
+/// read the year file at `path` one user at a time, handing each to `f` with only its days in
+/// `start..=end`. days outside the range are skipped while parsing and never built, so the memory
+/// used is bounded by the range instead of the file.
+/// the users are migrated like `ServerFile::load` does, once the version of the file is known,
+/// since it may come after them.
+pub fn stream_users(
+    path: &Path,
+    start: LouisEpoch,
+    end: LouisEpoch,
+    f: impl FnMut(User),
+) -> Result<ShardSummary, String> {
+    let _lock = match path.parent() {
+        Some(directory) => DirectoryLock::shared(directory)?,
+        None => None,
+    };
+    let file = File::open(path).map_err(|e| format!("failed to load Server \"{path:?}\": {e}"))?;
+    let reader = BufReader::new(Codec::of(path).reader(file)?);
+    let (summary, mut users, version) = serde_json::Deserializer::from_reader(reader)
+        .deserialize_map(FileVisitor { start, end })
+        .map_err(|e| format!("could not stream server file \"{path:?}\": {e}"))?;
+    migrate(version, users.iter_mut())
+        .map_err(|e| format!("could not migrate server file \"{path:?}\": {e}"))?;
+    users.into_iter().for_each(f);
+    Ok(summary)
+}
+
+struct FileVisitor {
+    start: LouisEpoch,
+    end: LouisEpoch,
+}
+impl<'de> Visitor<'de> for FileVisitor {
+    type Value = (ShardSummary, Vec<User>, Option<u64>);
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a server file")
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let (mut users, mut reactions, mut meta, mut version) = (None, None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "users" => {
+                    let mut streamed = Vec::new();
+                    map.next_value_seed(UsersSeed {
+                        start: self.start,
+                        end: self.end,
+                        f: &mut |user| streamed.push(user),
+                    })?;
+                    users = Some(streamed);
+                }
+                "reactions" => reactions = Some(map.next_value::<Vec<String>>()?),
+                "meta" => meta = Some(map.next_value::<Meta>()?),
+                "version" => version = map.next_value::<Option<u64>>()?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let users = users.ok_or(serde::de::Error::missing_field("users"))?;
+        let reactions = reactions.ok_or(serde::de::Error::missing_field("reactions"))?;
+        let meta = meta.ok_or(serde::de::Error::missing_field("meta"))?;
+        let summary = ShardSummary {
+            reactions,
+            first_day: meta.first_day,
+            last_day: meta.last_day,
+        };
+        Ok((summary, users, version))
+    }
+}
+
+struct UsersSeed<'f, F> {
+    start: LouisEpoch,
+    end: LouisEpoch,
+    f: &'f mut F,
+}
+impl<'de, F: FnMut(User)> DeserializeSeed<'de> for UsersSeed<'_, F> {
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+impl<'de, F: FnMut(User)> Visitor<'de> for UsersSeed<'_, F> {
+    type Value = ();
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of users")
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while map.next_key::<IgnoredAny>()?.is_some() {
+            let user = map.next_value_seed(UserSeed {
+                start: self.start,
+                end: self.end,
+            })?;
+            (self.f)(user);
+        }
+        Ok(())
+    }
+}
+
+struct UserSeed {
+    start: LouisEpoch,
+    end: LouisEpoch,
+}
+impl<'de> DeserializeSeed<'de> for UserSeed {
+    type Value = User;
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<User, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+impl<'de> Visitor<'de> for UserSeed {
+    type Value = User;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a user")
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<User, A::Error> {
+        let (mut id, mut name, mut days) = (None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "id" => id = Some(map.next_value::<u64>()?),
+                "name" => name = Some(map.next_value::<String>()?),
+                "days" => {
+                    days = Some(map.next_value_seed(DaysSeed {
+                        start: self.start,
+                        end: self.end,
+                    })?)
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let id = id.ok_or(serde::de::Error::missing_field("id"))?;
+        let name = name.ok_or(serde::de::Error::missing_field("name"))?;
+        let mut user = User::new(id, &name);
+        for (epoch, day) in days.ok_or(serde::de::Error::missing_field("days"))? {
+            user.insert_day(epoch, day);
+        }
+        Ok(user)
+    }
+}
+
+struct DaysSeed {
+    start: LouisEpoch,
+    end: LouisEpoch,
+}
+impl<'de> DeserializeSeed<'de> for DaysSeed {
+    type Value = HashMap<LouisEpoch, Day>;
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+impl<'de> Visitor<'de> for DaysSeed {
+    type Value = HashMap<LouisEpoch, Day>;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of days")
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut days = HashMap::new();
+        while let Some(epoch) = map.next_key::<LouisEpoch>()? {
+            if (self.start..=self.end).contains(&epoch) {
+                days.insert(epoch, map.next_value::<Day>()?);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(days)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::database::{
+        cache::ShardCache,
+        server::{ServerFile, ServerFiles},
+        storage::{CollectedRange, ShardSource, Storage},
+        test_dir,
+    };
+
+    fn files(directory: &Path, streaming: bool) -> ServerFiles {
+        let cache = Arc::new(Mutex::new(ShardCache::new(usize::MAX)));
+        let mut files = ServerFiles::with_cache(directory, "server", cache);
+        files.set_streaming(streaming);
+        files
+    }
+    fn users_of(collected: &CollectedRange) -> serde_json::Value {
+        serde_json::to_value(&collected.users).unwrap()
+    }
+
+    #[test]
+    fn days_outside_the_range_are_never_built() {
+        let path = test_dir("stream-range").join("server_2025.json");
+        // day 9 could not even be built, it is only ever skipped over.
+        fs::write(
+            &path,
+            r#"{"users": {"1": {"id": 1, "name": "louis", "days": {
+                "9": {"date": 0.0, "msg_hours": "not hours", "emoji_hours": {}},
+                "10": {"date": 0.0, "msg_hours": [1], "emoji_hours": {}}
+            }}}, "reactions": [], "meta": {"first_day": 9, "last_day": 10}}"#,
+        )
+        .unwrap();
+        let mut users = Vec::new();
+        let summary = stream_users(&path, 10, 10, |user| users.push(user)).unwrap();
+        assert_eq!((summary.first_day, summary.last_day), (9, 10));
+        assert_eq!(users[0].days().keys().collect::<Vec<_>>(), [&10]);
+        assert!(stream_users(&path, 0, 10, |_| ()).is_err());
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let path = test_dir("stream-version").join("server_2025.json");
+        fs::write(
+            &path,
+            r#"{"users": {}, "reactions": [], "meta": {"first_day": 0, "last_day": 0}, "version": 2}"#,
+        )
+        .unwrap();
+        let error = stream_users(&path, 0, LouisEpoch::MAX, |_| ())
+            .err()
+            .unwrap();
+        assert!(error.contains("schema version 2 is newer"), "{error}");
+    }
+
+    #[test]
+    fn streamed_queries_collect_what_loaded_ones_do() {
+        let directory = test_dir("stream-collect");
+        let mut written = files(&directory, false);
+        for (timestamp, messages) in [(1_750_000_000, 2), (1_760_000_000, 3), (1_781_000_000, 4)] {
+            let date = DateTime::from_timestamp(timestamp, 0).unwrap();
+            let updates = [
+                (1, "louis", messages, &[("👍", 1)][..], date),
+                (2, "clara", 1, &[][..], date),
+            ];
+            written.update(&updates, 1).unwrap();
+        }
+        written.flush().unwrap();
+        drop(written);
+        assert!(ServerFile::file_path_in(&directory, "server", 2026).exists());
+        // from inside 2025 up to the middle of 2026, and everything.
+        for (start, end) in [(100, 400), (0, LouisEpoch::MAX)] {
+            let streamed = files(&directory, true).collect(start, end).unwrap();
+            let loaded = files(&directory, false).collect(start, end).unwrap();
+            assert_eq!(users_of(&streamed), users_of(&loaded));
+            assert_eq!(streamed.years, loaded.years);
+            assert!(
+                streamed
+                    .loads
+                    .iter()
+                    .all(|load| load.source == ShardSource::Stream)
+            );
+        }
+    }
+}
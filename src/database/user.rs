@@ -1,9 +1,18 @@
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, hash_map::Entry};
 
-use crate::database::day::Day;
+use crate::database::day::{Day, Interner, Reaction};
 use crate::database::epoch::LouisEpoch;
 
+/// how `User::combine` treats a day present in both users.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MergeStrategy {
+    /// add the hourly message and reaction counts together.
+    Sum,
+    /// keep the day already in `self`, dropping the other one.
+    FirstWins,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct User {
     pub id: u64,
@@ -18,6 +27,32 @@ impl User {
             days: HashMap::new(),
         }
     }
+    pub fn days(&self) -> &HashMap<LouisEpoch, Day> {
+        &self.days
+    }
+    pub fn insert_day(&mut self, epoch: LouisEpoch, day: Day) {
+        self.days.insert(epoch, day);
+    }
+    /// rough number of bytes this user occupies in memory.
+    pub fn estimated_size(&self) -> usize {
+        size_of::<Self>()
+            + self.name.len()
+            + self
+                .days
+                .values()
+                .map(|d| size_of::<LouisEpoch>() + d.estimated_size())
+                .sum::<usize>()
+    }
+    /// share the reaction names of every day with the rest of the file, see `Interner`.
+    pub fn intern_reactions(&mut self, interner: &mut Interner) {
+        self.days
+            .values_mut()
+            .for_each(|d| d.intern_reactions(interner));
+    }
+    /// fold the hour vectors of every day to `HOURS` counts, see `Day::repair`.
+    pub fn repair(&mut self) {
+        self.days.values_mut().for_each(Day::repair);
+    }
     fn get_day(&self, day: u64) -> Option<&Day> {
         self.days.get(&day)
     }
@@ -40,7 +75,7 @@ impl User {
         &mut self,
         day: LouisEpoch,
         hour: usize,
-        reaction: &str,
+        reaction: &Reaction,
         count: usize,
     ) {
         if let Some(d) = self.days.get_mut(&day) {
@@ -50,25 +85,44 @@ impl User {
                 .increment_reaction(reaction, hour, count);
         }
     }
-    pub fn combine(self, other: Self, min: Option<LouisEpoch>, max: Option<LouisEpoch>) -> Self {
-        // iterate over days hashmap, filter days that are in min..max and not in self.days, add those to new return new
-        fn less_than(lhs: &&u64, rhs: Option<usize>) -> bool {
-            rhs.map(|rhs| **lhs < rhs as u64).unwrap_or(true)
-        }
-        fn greater_than(lhs: &&u64, rhs: Option<usize>) -> bool {
-            rhs.map(|rhs| **lhs > rhs as u64).unwrap_or(true)
+    /// merge the days of `other` within `min..=max` into `self`,
+    /// `strategy` decides what happens to days both users have.
+    pub fn combine(
+        self,
+        other: Self,
+        min: Option<LouisEpoch>,
+        max: Option<LouisEpoch>,
+        strategy: MergeStrategy,
+    ) -> Self {
+        let mut new = self;
+        for (e, d) in other.filter(min, max).days {
+            match new.days.entry(e) {
+                Entry::Occupied(mut occupied_entry) => match strategy {
+                    MergeStrategy::Sum => occupied_entry.get_mut().merge(&d),
+                    MergeStrategy::FirstWins => (),
+                },
+                Entry::Vacant(vacant_entry) => {
+                    vacant_entry.insert(d);
+                }
+            }
         }
-        let mut new = self.clone();
-        other
-            .filter(min, max)
-            .days
-            .iter()
-            .filter(|(e, _)| !(self.days.contains_key(e)))
-            .for_each(|(e, d)| {
-                new.days.insert(*e, d.clone());
-            });
         new
     }
+    /// a copy holding only the days within `min..=max`, without cloning the others first.
+    pub fn filtered(&self, min: Option<LouisEpoch>, max: Option<LouisEpoch>) -> Self {
+        Self {
+            id: self.id,
+            name: self.name.clone(),
+            days: self
+                .days
+                .iter()
+                .filter(|(e, _)| {
+                    min.map(|m| m <= **e).unwrap_or(true) && max.map(|m| **e <= m).unwrap_or(true)
+                })
+                .map(|(e, d)| (*e, d.clone()))
+                .collect(),
+        }
+    }
     pub fn filter(mut self, min: Option<LouisEpoch>, max: Option<LouisEpoch>) -> Self {
         self.days = self
             .days
@@ -88,3 +142,44 @@ impl User {
             .fold(0, |acc, d| acc + d.total_reactions_of(reaction))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // both users have day 10, only `other` has day 11.
+    fn overlapping() -> (User, User) {
+        let thumbs: Reaction = "👍".into();
+        let mut user = User::new(1, "louis");
+        user.update_message_count(10, 12, 2);
+        user.update_reaction_count(10, 12, &thumbs, 1);
+        let mut other = User::new(1, "louis");
+        other.update_message_count(10, 12, 3);
+        other.update_message_count(10, 13, 1);
+        other.update_reaction_count(10, 12, &thumbs, 4);
+        other.update_reaction_count(10, 13, &"🎉".into(), 1);
+        other.update_message_count(11, 0, 5);
+        (user, other)
+    }
+
+    #[test]
+    fn sum_adds_overlapping_days_together() {
+        let (user, other) = overlapping();
+        let combined = user.combine(other, None, None, MergeStrategy::Sum);
+        assert_eq!(combined.get_day(10).unwrap().total(), 6);
+        assert_eq!(combined.get_day(11).unwrap().total(), 5);
+        assert_eq!(combined.sum_reactions("👍"), 5);
+        assert_eq!(combined.sum_reactions("🎉"), 1);
+    }
+
+    #[test]
+    fn first_wins_keeps_overlapping_days_as_they_were() {
+        let (user, other) = overlapping();
+        let combined = user.combine(other, None, None, MergeStrategy::FirstWins);
+        assert_eq!(combined.get_day(10).unwrap().total(), 2);
+        assert_eq!(combined.get_day(11).unwrap().total(), 5);
+        assert_eq!(combined.sum_reactions("👍"), 1);
+        assert_eq!(combined.sum_reactions("🎉"), 0);
+        assert_eq!(combined.sum(), 7);
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, MutexGuard},
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::database::{
+    persist::write_atomic,
+    server::{BATCH_CACHE_FILE, BatchCache},
+};
+
+/// name of the watermarks in the database directory.
+pub const WATERMARKS_FILE: &str = "watermarks.json";
+
+/// a message in a channel, discord ids grow with the time they were sent at.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Mark {
+    pub id: u64,
+    pub timestamp: f64,
+}
+
+/// the marks of one channel.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct ChannelMarks {
+    /// the first message counted live, backfills stop right before it.
+    live_from: Option<Mark>,
+    /// the newest message counted by a backfill, live counting skips anything up to it.
+    backfilled: Option<Mark>,
+    /// the newest message of each author counted by importing their own export,
+    /// backfills skip their messages up to it.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    imported: HashMap<String, Mark>,
+    // claimed by batches which are being counted, the marks above only move once a batch
+    // is journaled along with its `Checkpoint`. never persisted.
+    #[serde(skip)]
+    claimed_backfill: Option<Mark>,
+    #[serde(skip)]
+    claimed_imports: HashMap<String, Mark>,
+}
+
+impl ChannelMarks {
+    /// whether message `id` of `author_id` read from an export was never counted. only messages
+    /// sent before live counting began and past the backfill frontier and earlier imports are left.
+    pub fn admits(&self, author_id: usize, id: u64) -> bool {
+        self.live_from.is_none_or(|l| id < l.id)
+            && self.backfilled().is_none_or(|b| id > b.id)
+            && self.imported(author_id).is_none_or(|i| id > i.id)
+    }
+    /// the newest message counted by a backfill, or claimed by one which is counting.
+    pub fn backfilled(&self) -> Option<Mark> {
+        newest(self.backfilled, self.claimed_backfill)
+    }
+    fn imported(&self, author_id: usize) -> Option<Mark> {
+        let author = author_id.to_string();
+        newest(
+            self.imported.get(&author).copied(),
+            self.claimed_imports.get(&author).copied(),
+        )
+    }
+}
+
+fn newest(a: Option<Mark>, b: Option<Mark>) -> Option<Mark> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b.id > a.id { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+/// the marks a batch moves, journaled in the same record as its updates so the two are
+/// never persisted apart. applying it again, e.g. on recovery, changes nothing.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct Checkpoint {
+    pub server_id: usize,
+    pub channel_id: usize,
+    /// where a backfill of the channel has read up to, see `BatchCache::pointer`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pointer: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backfilled: Option<Mark>,
+    /// the author and the newest message of theirs an import counted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imported: Option<(usize, Mark)>,
+    /// the first message counted live, only kept if there was none before.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub live_from: Option<Mark>,
+}
+impl Checkpoint {
+    pub fn new(server_id: usize, channel_id: usize) -> Self {
+        Self {
+            server_id,
+            channel_id,
+            ..Self::default()
+        }
+    }
+    /// whether applying it moves nothing.
+    pub fn is_empty(&self) -> bool {
+        self.pointer.is_none()
+            && self.backfilled.is_none()
+            && self.imported.is_none()
+            && self.live_from.is_none()
+    }
+}
+
+/// duplicates which were not counted in a channel.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Skipped {
+    /// by a backfill, they were already counted by an earlier one.
+    pub backfill: usize,
+    /// by live counting, a backfill counted them first.
+    pub live: usize,
+    /// by an import, they were counted live, by a backfill or by an earlier import.
+    pub import: usize,
+    /// by importing a channel archive, they were counted live, by a backfill or by an import.
+    pub archive: usize,
+}
+
+/// where live counting and backfills meet in every channel, so no message is counted by both.
+/// shared between the two behind a mutex, each checks and moves the marks under the same lock.
+pub struct Watermarks {
+    path: PathBuf,
+    servers: HashMap<String, HashMap<String, ChannelMarks>>,
+    // since the watermarks were opened, keyed by server and channel.
+    skipped: BTreeMap<(usize, usize), Skipped>,
+}
+impl Watermarks {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let servers = if path.exists() {
+            let file = File::open(path).map_err(|e| format!("failed to open {path:?}: {e}"))?;
+            serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| format!("could not read {path:?}: {e}"))?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            servers,
+            skipped: BTreeMap::new(),
+        })
+    }
+    pub fn flush(&self) -> Result<(), String> {
+        let serialized = serde_json::to_vec(&self.servers)
+            .map_err(|e| format!("could not serialize {:?}: {e}", self.path))?;
+        write_atomic(&self.path, &serialized)
+    }
+    /// the checkpoint the live event handler counts `message` of `channel` with, `None` if a
+    /// backfill already counted it or will count it. the first message counted in a channel is where backfills
+    /// of it stop from then on, its checkpoint moves `live_from` there.
+    /// see `ServerDatabase::count_live`.
+    pub fn admit_live(
+        &mut self,
+        server_id: usize,
+        channel_id: usize,
+        message: Mark,
+    ) -> Option<Checkpoint> {
+        let marks = self.channel(server_id, channel_id);
+        // messages before `live_from` are left to backfills, even those no backfill reached yet.
+        if marks.backfilled().is_some_and(|b| message.id <= b.id)
+            || marks.live_from.is_some_and(|l| message.id < l.id)
+        {
+            self.skipped
+                .entry((server_id, channel_id))
+                .or_default()
+                .live += 1;
+            return None;
+        }
+        Some(Checkpoint {
+            live_from: Some(message).filter(|_| marks.live_from.is_none()),
+            ..Checkpoint::new(server_id, channel_id)
+        })
+    }
+    pub fn live_from(&self, server_id: usize, channel_id: usize) -> Option<Mark> {
+        self.marks(server_id, channel_id)?.live_from
+    }
+    /// the newest message of `channel_id` counted or claimed by a backfill.
+    pub fn backfilled(&self, server_id: usize, channel_id: usize) -> Option<Mark> {
+        self.marks(server_id, channel_id)?.backfilled()
+    }
+    /// move the marks of the channel of `checkpoint` up to it, marks already past it stay.
+    pub fn apply(&mut self, checkpoint: &Checkpoint) {
+        let marks = self.channel(checkpoint.server_id, checkpoint.channel_id);
+        marks.live_from = marks.live_from.or(checkpoint.live_from);
+        if let Some(backfilled) = checkpoint.backfilled {
+            marks.backfilled = newest(marks.backfilled, Some(backfilled));
+        }
+        if let Some((author_id, imported)) = checkpoint.imported {
+            let author = author_id.to_string();
+            let previous = marks.imported.get(&author).copied();
+            if let Some(mark) = newest(previous, Some(imported)) {
+                marks.imported.insert(author, mark);
+            }
+        }
+    }
+    /// claim the messages of `channel_id` up to `claim` for a backfill until its checkpoint is
+    /// applied, live counting and imports skip them. returns the previous claim, to put back
+    /// if the batch could not be counted.
+    pub fn claim_backfilled(
+        &mut self,
+        server_id: usize,
+        channel_id: usize,
+        claim: Option<Mark>,
+    ) -> Option<Mark> {
+        std::mem::replace(
+            &mut self.channel(server_id, channel_id).claimed_backfill,
+            claim,
+        )
+    }
+    /// `claim_backfilled` for an import of the messages of `author_id`.
+    pub fn claim_imported(
+        &mut self,
+        server_id: usize,
+        channel_id: usize,
+        author_id: usize,
+        claim: Option<Mark>,
+    ) -> Option<Mark> {
+        let claimed = &mut self.channel(server_id, channel_id).claimed_imports;
+        match claim {
+            Some(mark) => claimed.insert(author_id.to_string(), mark),
+            None => claimed.remove(&author_id.to_string()),
+        }
+    }
+    /// the newest message of `author_id` in `channel_id` counted or claimed by an import.
+    pub fn imported(&self, server_id: usize, channel_id: usize, author_id: usize) -> Option<Mark> {
+        self.marks(server_id, channel_id)?.imported(author_id)
+    }
+    /// whether message `id` of `author_id`, read from an export of their own, was never counted.
+    pub fn admit_import(
+        &self,
+        server_id: usize,
+        channel_id: usize,
+        author_id: usize,
+        id: u64,
+    ) -> bool {
+        self.marks(server_id, channel_id)
+            .is_none_or(|marks| marks.admits(author_id, id))
+    }
+    /// a copy of the marks of `channel_id`, to check messages against without holding the lock.
+    pub fn channel_marks(&self, server_id: usize, channel_id: usize) -> ChannelMarks {
+        self.marks(server_id, channel_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+    pub fn skip_imported(&mut self, server_id: usize, channel_id: usize, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.skipped
+            .entry((server_id, channel_id))
+            .or_default()
+            .import += count;
+    }
+    pub fn skip_archived(&mut self, server_id: usize, channel_id: usize, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.skipped
+            .entry((server_id, channel_id))
+            .or_default()
+            .archive += count;
+    }
+    pub fn skip_backfilled(&mut self, server_id: usize, channel_id: usize, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.skipped
+            .entry((server_id, channel_id))
+            .or_default()
+            .backfill += count;
+    }
+    /// every channel with skipped duplicates, by server then channel.
+    pub fn report(&self) -> Vec<(usize, usize, Skipped)> {
+        self.skipped
+            .iter()
+            .map(|((server, channel), skipped)| (*server, *channel, *skipped))
+            .collect()
+    }
+    fn marks(&self, server_id: usize, channel_id: usize) -> Option<&ChannelMarks> {
+        self.servers
+            .get(&server_id.to_string())?
+            .get(&channel_id.to_string())
+    }
+    fn channel(&mut self, server_id: usize, channel_id: usize) -> &mut ChannelMarks {
+        self.servers
+            .entry(server_id.to_string())
+            .or_default()
+            .entry(channel_id.to_string())
+            .or_default()
+    }
+}
+
+// the marks of every database directory opened by the process, see `Marks::shared`.
+static SHARED: LazyLock<Mutex<HashMap<PathBuf, Marks>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// the watermarks and backfill pointers of every server of a database directory.
+/// a database applies the checkpoints it journals to them and persists them before it
+/// drops its journal, backfills and imports read them to know where to go on from.
+#[derive(Clone)]
+pub struct Marks {
+    pub watermarks: Arc<Mutex<Watermarks>>,
+    pub cache: Arc<Mutex<BatchCache>>,
+}
+impl Marks {
+    /// the `WATERMARKS_FILE` and `BATCH_CACHE_FILE` in `directory`, held by these marks alone.
+    pub fn open(directory: &Path) -> Result<Self, String> {
+        Ok(Self {
+            watermarks: Arc::new(Mutex::new(Watermarks::open(
+                &directory.join(WATERMARKS_FILE),
+            )?)),
+            cache: Arc::new(Mutex::new(BatchCache::new(
+                &directory.join(BATCH_CACHE_FILE),
+            )?)),
+        })
+    }
+    /// the marks of `directory` shared by every database, backfill and import of the process,
+    /// opened on first use. the servers of a directory all write the same files.
+    pub fn shared(directory: &Path) -> Result<Self, String> {
+        let mut shared = SHARED
+            .lock()
+            .map_err(|_| "shared marks lock poisoned".to_string())?;
+        if let Some(marks) = shared.get(directory) {
+            return Ok(marks.clone());
+        }
+        let marks = Self::open(directory)?;
+        shared.insert(directory.to_path_buf(), marks.clone());
+        Ok(marks)
+    }
+    /// forget the shared marks of `directory` so they are read again on next use,
+    /// e.g. once a backup replaced their files.
+    pub fn reload_shared(directory: &Path) -> Result<(), String> {
+        SHARED
+            .lock()
+            .map_err(|_| "shared marks lock poisoned".to_string())?
+            .remove(directory);
+        Ok(())
+    }
+    pub fn watermarks(&self) -> Result<MutexGuard<'_, Watermarks>, String> {
+        self.watermarks
+            .lock()
+            .map_err(|_| "watermarks lock poisoned".to_string())
+    }
+    pub fn cache(&self) -> Result<MutexGuard<'_, BatchCache>, String> {
+        self.cache
+            .lock()
+            .map_err(|_| "batch cache lock poisoned".to_string())
+    }
+    /// move the marks and pointer of a journaled batch, see `Watermarks::apply`.
+    pub fn apply(&self, checkpoint: &Checkpoint) -> Result<(), String> {
+        self.watermarks()?.apply(checkpoint);
+        if let Some(pointer) = checkpoint.pointer {
+            let mut cache = self.cache()?;
+            if cache
+                .pointer(checkpoint.server_id, checkpoint.channel_id)
+                .is_none_or(|p| p < pointer)
+            {
+                cache.log_pointer(checkpoint.server_id, checkpoint.channel_id, pointer);
+            }
+        }
+        Ok(())
+    }
+    pub fn flush(&self) -> Result<(), String> {
+        self.watermarks()?.flush()?;
+        self.cache()?.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_dir;
+
+    fn mark(id: u64) -> Mark {
+        Mark {
+            id,
+            timestamp: id as f64,
+        }
+    }
+
+    #[test]
+    fn live_counting_skips_what_a_backfill_counted() {
+        let path = test_dir("watermarks-live").join(WATERMARKS_FILE);
+        let mut watermarks = Watermarks::open(&path).unwrap();
+        watermarks.apply(&Checkpoint {
+            backfilled: Some(mark(100)),
+            ..Checkpoint::new(1, 10)
+        });
+        assert_eq!(watermarks.admit_live(1, 10, mark(100)), None);
+        let first = watermarks.admit_live(1, 10, mark(101)).unwrap();
+        assert_eq!(first.live_from, Some(mark(101)));
+        // nothing moves until the message is journaled along with its checkpoint.
+        assert_eq!(watermarks.live_from(1, 10), None);
+        watermarks.apply(&first);
+        // only the first message counted live is where backfills stop.
+        let second = watermarks.admit_live(1, 10, mark(102)).unwrap();
+        assert!(second.is_empty());
+        assert_eq!(watermarks.live_from(1, 10), Some(mark(101)));
+        // other channels are untouched.
+        assert!(watermarks.admit_live(1, 11, mark(50)).is_some());
+        assert_eq!(watermarks.report()[0].2.live, 1);
+    }
+
+    #[test]
+    fn imports_only_admit_messages_nothing_else_counted() {
+        let marks = ChannelMarks {
+            live_from: Some(mark(200)),
+            backfilled: Some(mark(100)),
+            imported: HashMap::from([("7".to_string(), mark(150))]),
+            ..ChannelMarks::default()
+        };
+        assert!(!marks.admits(7, 100));
+        assert!(!marks.admits(7, 150));
+        assert!(marks.admits(7, 151));
+        assert!(!marks.admits(7, 200));
+        // the import mark is per author.
+        assert!(marks.admits(8, 120));
+    }
+
+    #[test]
+    fn survive_reopening() {
+        let path = test_dir("watermarks-reopen").join(WATERMARKS_FILE);
+        let mut watermarks = Watermarks::open(&path).unwrap();
+        watermarks.apply(&Checkpoint {
+            backfilled: Some(mark(100)),
+            imported: Some((7, mark(90))),
+            live_from: Some(mark(300)),
+            ..Checkpoint::new(1, 10)
+        });
+        // claims are never persisted.
+        watermarks.claim_backfilled(1, 10, Some(mark(200)));
+        watermarks.flush().unwrap();
+        let reopened = Watermarks::open(&path).unwrap();
+        assert_eq!(reopened.backfilled(1, 10), Some(mark(100)));
+        assert_eq!(reopened.imported(1, 10, 7), Some(mark(90)));
+        assert_eq!(reopened.live_from(1, 10), Some(mark(300)));
+        // what was skipped is only reported for the run it happened in.
+        assert!(reopened.report().is_empty());
+    }
+}